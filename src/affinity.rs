@@ -0,0 +1,52 @@
+use std::collections::BTreeSet;
+
+/// CPU numbers, in order.
+pub type CpuSet = BTreeSet<usize>;
+
+/// Parse a CPU list like "0-3,6" (the format of isolcpus and taskset -c).
+pub fn parse_cpu_list(s: &str) -> Result<CpuSet, String> {
+    let mut cpus = BTreeSet::new();
+    for part in s.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        let parse = |n: &str| n.trim().parse::<usize>().map_err(|e| format!("bad cpu \"{}\" in \"{}\": {}", n, s, e));
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let (first, last) = (parse(first)?, parse(last)?);
+                if first > last {
+                    return Err(format!("bad cpu range \"{}\"", part));
+                }
+                cpus.extend(first..=last);
+            }
+            None => {
+                cpus.insert(parse(part)?);
+            }
+        }
+    }
+    if cpus.is_empty() {
+        return Err(format!("no cpus in \"{}\"", s));
+    }
+    return Ok(cpus);
+}
+
+/// CPUs `pid` may run on, 0 is the calling thread.
+pub fn get_affinity(pid: u32) -> Result<CpuSet, String> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    if unsafe { libc::sched_getaffinity(pid as libc::pid_t, std::mem::size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+        return Err(format!("sched_getaffinity failed: {}", std::io::Error::last_os_error()));
+    }
+    return Ok((0..libc::CPU_SETSIZE as usize).filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) }).collect());
+}
+
+/// Restrict `pid` to `cpus`, 0 is the calling thread. Threads and children created afterwards inherit it.
+pub fn set_affinity(pid: u32, cpus: &CpuSet) -> Result<(), String> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    for cpu in cpus {
+        if *cpu >= libc::CPU_SETSIZE as usize {
+            return Err(format!("cpu {} out of range", cpu));
+        }
+        unsafe { libc::CPU_SET(*cpu, &mut set) };
+    }
+    if unsafe { libc::sched_setaffinity(pid as libc::pid_t, std::mem::size_of::<libc::cpu_set_t>(), &set) } != 0 {
+        return Err(format!("sched_setaffinity to {:?} failed: {}", cpus, std::io::Error::last_os_error()));
+    }
+    return Ok(());
+}
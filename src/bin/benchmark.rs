@@ -1,23 +1,97 @@
 
-use std::{io::{BufRead, BufReader, BufWriter, Write}, process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitCode, Stdio}, time::{Duration, SystemTime}};
+use std::{collections::BTreeMap, fmt::Write, os::unix::process::ExitStatusExt, process::ExitCode, sync::{atomic::{AtomicBool, Ordering}, OnceLock}, time::{Duration, Instant}};
 use clap::Parser;
+use regex::Regex;
+use clap_num::maybe_hex;
+use scanmem_tests::affinity::{get_affinity, parse_cpu_list, set_affinity, CpuSet};
+use scanmem_tests::child_process::{enable_echo_timestamps, ChildProcess, TIMEOUT_ERROR};
+use scanmem_tests::{default_synthetic_load_path, find_program, log_print, log_println, scanmem_args, DEFAULT_PID_ARG_TEMPLATE, DEFAULT_THREADS_ARG_TEMPLATE};
+use scanmem_tests::log::set_log_file;
 
-static SYNTHETIC_LOAD_NAME: &str = "synthetic_load";
+/// Exit status when every scenario passed, see `EXIT_STATUS_HELP`.
+const EXIT_SUCCESS: u8 = 0;
+/// Exit status when a scenario failed (error, crash, --timeout, --check-integrity), or the run couldn't be set up.
+const EXIT_FAILURE: u8 = 1;
+/// Exit status when every scenario ran but --expect-matches didn't hold. 2 is taken by clap's usage errors.
+const EXIT_MATCH_MISMATCH: u8 = 3;
+
+static EXIT_STATUS_HELP: &str = "Exit status:
+  0  every scenario passed
+  1  a scenario failed (error, crash, --timeout, --check-integrity), or the run couldn't be set up
+  2  invalid arguments
+  3  every scenario ran, but a match count differed from --expect-matches";
 
 #[derive(Parser)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, after_help = EXIT_STATUS_HELP)]
 struct Cli {
     /// Path to scanmem program to run.
     #[arg(long)]
     scanmem_program: String,
 
+    /// Name of the scanmem build under test, like "master" or "simd", to tell runs apart in concatenated --csv files.
+    /// Defaults to the file name of --scanmem-program.
+    #[arg(long)]
+    variant: Option<String>,
+
     /// List of scanmem commands to perform on the syntetic load, it should be a list of command seperated by the ';' character, and need to end with the 'exit' command. Example: "= 1; exit".
+    #[arg(long, required_unless_present = "command_set")]
+    scanmem_commands: Option<String>,
+
+    /// A named set of scanmem commands, as "NAME=COMMANDS" or just the NAME of a set in --command-set-file, repeat
+    /// for more. Every set is run for every size, seed and thread count. --scanmem-commands, when also given, is the
+    /// set named "default".
+    #[arg(long)]
+    command_set: Vec<String>,
+
+    /// File of command sets --command-set can select by name, one per line as: find-ones = "= 1; exit".
+    /// The quotes are optional, empty lines and lines starting with '#' are skipped.
     #[arg(long)]
-    scanmem_commands: String,
+    command_set_file: Option<String>,
+
+    /// Print the scenarios the sweep would run and an estimate of its run time, and exit. The estimate comes from one
+    /// calibration iteration of the first scenario at the smallest size and one at the largest size up to 64 MiB, which
+    /// do start synthetic_load and scanmem.
+    #[arg(long, default_value_t = false)]
+    dry_run: bool,
+
+    /// Treat --scanmem-commands as a narrowing workflow: commands starting with "load:" go to synthetic_load in between
+    /// the scanmem commands, e.g. "= 1; load: fill-stride 2 0x1000; = 1; exit", which overwrites every 4096th byte in
+    /// between the two scans. Commands taking absolute addresses don't know where the memory is, use the offset based
+    /// ones. Every iteration runs the whole sequence, and the time and match count of each scanmem command are
+    /// recorded. A failing load: command fails the scenario. Use --reset-command to undo the mutations.
+    #[arg(long, default_value_t = false)]
+    workflow: bool,
+
+    /// Prompt scanmem prints when it is ready for the next command, matched against the end of its output.
+    #[arg(long, default_value = "> ")]
+    scanmem_prompt: String,
+
+    /// How the scanmem program is told the pid to attach to. "{pid}" is replaced with synthetic_load's pid, and the
+    /// result is split on whitespace into arguments, so "-p {pid}" gives two and "{pid}" a positional one.
+    #[arg(long, default_value = DEFAULT_PID_ARG_TEMPLATE, allow_hyphen_values = true)]
+    pid_arg_template: String,
+
+    /// How the scanmem program is told the thread count, like --pid-arg-template with "{threads}". Not passed for -1.
+    #[arg(long, default_value = DEFAULT_THREADS_ARG_TEMPLATE, allow_hyphen_values = true)]
+    threads_arg_template: String,
 
     /// Number of threads scanmem will use to scan, set to -1 if multi threading is not supported by the scanmem program. 
-    #[arg(short = 't', long, default_value_t = -1)]
-    nthreads: i32,
+    /// A comma separated list sweeps every count for each size, e.g. "1,2,4,8".
+    #[arg(short = 't', long, default_value = "-1", value_delimiter = ',', allow_hyphen_values = true)]
+    nthreads: Vec<i32>,
+
+    /// CPUs scanmem runs on, like "2-7" [default: all CPUs not in --harness-cpus].
+    #[arg(long)]
+    cpu_affinity: Option<String>,
+
+    /// CPUs the benchmark itself and synthetic_load run on, keep them disjoint from --cpu-affinity (e.g. isolcpus) so
+    /// the harness doesn't steal cycles from scanmem.
+    #[arg(long)]
+    harness_cpus: Option<String>,
+
+    /// Comma separated sizes of the synthetic load to run (in bytes), instead of the --minbytes to --maxbytes sweep.
+    #[arg(long, value_delimiter = ',', value_parser=maybe_hex::<u64>)]
+    sizes: Option<Vec<u64>>,
 
     /// Minimum size of synthetic load at start (in bytes).
     #[arg(long, default_value_t = 0x1_000_000u64)]
@@ -25,6 +99,10 @@ struct Cli {
     /// Maximum size of synthetic load at end (in bytes).
     #[arg(long, default_value_t = 0x1_000_000u64)]
     maxbytes: u64,
+    /// Skip sizes whose synthetic loads plus scanmem wouldn't fit in this many bytes, 0 disables the check
+    /// [default: 3/4 of MemAvailable]. scanmem is counted as needing as much as one synthetic load, for its match list.
+    #[arg(long)]
+    max_size_bytes: Option<u64>,
     /// Fixed increment added to size between each run (in bytes).
     #[arg(long, default_value_t = 0x1_000_000u64)]
     stepbytes: u64,
@@ -32,190 +110,843 @@ struct Cli {
     #[arg(long, default_value_t = 1.0f64)]
     stepfactor: f64,
 
-    /// Number of iterations per scenario.
+    /// Seed used to fill the synthetic load memory (fill-random).
+    #[arg(long, default_value_t = 0x1u64, value_parser=maybe_hex::<u64>)]
+    seed: u64,
+
+    /// How each size's fill seed is derived from --seed: "fixed" uses it as is, "per-size" mixes it with the size so sizes don't share content.
+    #[arg(long, default_value = "fixed", value_parser = ["fixed", "per-size"])]
+    seed_scheme: String,
+
+    /// Number of fill seeds to run every size with. The first is the --seed derived one, the others are mixed from it.
+    /// The aggregates then pool all seeds of a size, with a per seed breakdown to show how much the content matters.
+    #[arg(long, default_value_t = 1)]
+    seeds: usize,
+
+    /// Path to the synthetic_load program [default: next to this executable].
+    #[arg(long)]
+    synthetic_load_program: Option<String>,
+
+    /// Allocation backend the synthetic load uses for its memory region.
+    #[arg(long, default_value = "heap", value_parser = ["heap", "mmap"])]
+    backend: String,
+
+    /// Generator the synthetic load fills its memory with.
+    #[arg(long, default_value = "pcg", value_parser = ["pcg", "xoshiro", "chacha"])]
+    rng: String,
+
+    /// Before every iteration, have synthetic_load page out its memory (madvise-pageout) so each scan starts cold.
+    /// Anonymous memory can only be paged out to swap, so without swap the memory stays resident, which is warned about
+    /// once, and the scans are not cold. Also drops the kernel page cache through /proc/sys/vm/drop_caches, which needs root and is skipped
+    /// otherwise.
+    #[arg(long, default_value_t = false)]
+    drop_caches: bool,
+
+    /// synthetic_load command sent between iterations to restore a known state after scanmem commands that write
+    /// memory, e.g. "fill-random {seed}". "{seed}" is replaced with the scenario's fill seed, which stays the same for
+    /// all its iterations, so every iteration scans the same content. Not included in the iteration times.
+    #[arg(long)]
+    reset_command: Option<String>,
+
+    /// Have synthetic_load checksum its memory before and after every scan, and fail the scenario if scanmem changed it.
+    /// Only for read-only scanmem commands, and not with --workflow whose load: commands change the memory on purpose.
+    /// Not included in the iteration times.
+    #[arg(long, default_value_t = false, conflicts_with = "workflow")]
+    check_integrity: bool,
+
+    /// How long to wait after scanmem has detached from synthetic_load before telling it to exit, in milliseconds.
+    #[arg(long, default_value_t = 10)]
+    detach_settle_ms: u64,
+
+    /// Before the sweep, time copying a large buffer to estimate the memory bandwidth, and report scanmem's throughput
+    /// as a fraction of it so results compare across machines. Runs single threaded on the harness CPUs.
+    #[arg(long, default_value_t = false)]
+    measure_bandwidth: bool,
+
+    /// Number of synthetic_load processes to start per scenario, all of the same size and seed. scanmem attaches to one
+    /// pid at a time, so every iteration runs it once per target and the iteration time is the sum.
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    target_count: u64,
+
+    /// Number of iterations per scenario, the minimum with --min-time or --target-cv.
     #[arg(short = 'n', long, default_value_t = 20)]
     iterations: usize,
 
-    /// Timeout test if time elapsed is longer than specified (in seconds), 0 disables timeout.
+    /// Keep adding iterations until their times add up to at least this many seconds.
+    #[arg(long)]
+    min_time: Option<f64>,
+
+    /// Keep adding iterations until the coefficient of variation (stddev / mean) of their times is at most this percentage.
+    #[arg(long)]
+    target_cv: Option<f64>,
+
+    /// Mark scenarios whose coefficient of variation ends up above this percentage as unreliable. They still count,
+    /// the mark only warns that the machine was too noisy to trust their numbers.
+    #[arg(long)]
+    max_cv: Option<f64>,
+
+    /// Most iterations per scenario with --min-time or --target-cv, whether or not they were reached.
+    #[arg(long, default_value_t = 1000)]
+    max_iterations: usize,
+
+    /// Number of times to run the whole size sweep, results are also aggregated per size across repeats.
+    #[arg(long, default_value_t = 1)]
+    repeats: usize,
+
+    /// Fail, with exit status 3, if scanmem reports a different match count than this after any iteration. Without
+    /// it, a --workflow command set with exactly one "load: plant-values" expects that command's count.
+    #[arg(long)]
+    expect_matches: Option<u64>,
+
+    /// Fail the scenario when a single scanmem run takes longer than this (in seconds), scanmem is then terminated.
+    /// Also bounds how long synthetic_load gets to exit at the end of a scenario. 0 disables timeout. A wrapper script
+    /// around scanmem has to exec it, only the process started is terminated.
     #[arg(short = 'T', long, default_value_t = 0)]
     timeout: u64,
 
     /// Echo child process stdout and stderr in parent stdout and stderr.
     #[arg(short = 'v', long, default_value_t = false)]
     verbose: bool,
+
+    /// How the final report is printed, "debug" dumps every field including the raw timings.
+    #[arg(long, default_value = "table", value_parser = ["table", "debug"])]
+    format: String,
+
+    /// Also write the pooled aggregates to this file as CSV, one row per size, command set and thread count, with the
+    /// report schema version, the run's start time, --variant and the `--scanmem-program --version` line on every row so
+    /// concatenated files stay attributable.
+    #[arg(long)]
+    csv: Option<String>,
+
+    /// Number of the last scanmem input and output lines to attach to a failed scenario.
+    #[arg(long, default_value_t = 50)]
+    capture_lines: usize,
+
+    /// Also write all progress and verbose output to this file.
+    #[arg(long)]
+    log_file: Option<String>,
+
+    /// Prefix echoed child process lines with the time since the benchmark started.
+    #[arg(long, default_value_t = false)]
+    timestamps: bool,
+}
+
+/// How many iterations a scenario runs.
+#[derive(Default, Debug, Clone, Copy)]
+struct IterationBudget {
+    min_iterations: usize,
+    // only used with min_time or target_cv
+    max_iterations: usize,
+    min_time: Option<Duration>,
+    // as a fraction, not a percentage
+    target_cv: Option<f64>,
+}
+
+/// How every scenario of a run spawns and drives scanmem and synthetic_load.
+#[derive(Default, Debug)]
+struct ScenarioSetup {
+    scanmem_program: String,
+    workflow: bool,
+    scanmem_prompt: String,
+    pid_arg_template: String,
+    threads_arg_template: String,
+    // None when left to the scheduler
+    scanmem_cpus: Option<CpuSet>,
+    capture_lines: usize,
+    synthetic_load_program: String,
+    synthetic_load_backend: String,
+    synthetic_load_rng: String,
+    target_count: usize,
+    // with "{seed}" still in it, replaced per scenario
+    reset_command: Option<String>,
+    drop_caches: bool,
+    check_integrity: bool,
+    detach_settle: Duration,
+    // None when not limited
+    timeout: Option<Duration>,
+    verbose: bool,
+}
+
+/// What differs between the scenarios of a run.
+#[derive(Default, Debug)]
+struct Scenario<'a> {
+    scanmem_commands: &'a [&'a str],
+    nthreads: i32,
+    synthetic_load_size: u64,
+    synthetic_load_random_seed: u64,
+    budget: IterationBudget,
 }
 
 #[derive(Default, Debug)]
 struct BenchmarkTiming {
+    // everything before the first iteration, the sum of the phases below
     setup_time: Duration,
+    // starting synthetic_load, until it signals ready on its --ready-fd
+    spawn_time: Duration,
+    // set-memory-size
+    allocation_time: Duration,
+    // fill-random
+    fill_time: Duration,
     benchmark_times: Vec<Duration>,
+    // when each of benchmark_times started, since the start of the run, to line iterations up with external profiles
+    start_offsets: Vec<Duration>,
     total_time: Duration
 }
 
+/// Context switches of one scanmem run, from its rusage when reaped.
+#[derive(Default, Debug)]
+struct ContextSwitches {
+    voluntary: i64,
+    involuntary: i64,
+}
+
+/// One scanmem command of a --workflow iteration.
+#[derive(Default, Debug)]
+struct ScanStep {
+    command: String,
+    // just the scanmem command, the synthetic_load commands before it are not included
+    time: Duration,
+    // matches scanmem reported after this command, None if it reported none or --capture-lines is 0
+    match_count: Option<u64>,
+}
+
+/// One scanmem run, there is one per target in every iteration.
+#[derive(Default, Debug)]
+struct IterationStats {
+    // index of the synthetic_load scanned, 0 without --target-count
+    target: usize,
+    time: Duration,
+    // waiting for the stderr reader to catch up with --workflow step match counts, while scanmem idles at its
+    // prompt. Not part of `time`
+    stderr_wait: Duration,
+    context_switches: ContextSwitches,
+    // matches scanmem reported after its last scan, None if it never reported any
+    match_count: Option<u64>,
+    // how scanmem exited, exactly one of them is set
+    exit_code: Option<i32>,
+    exit_signal: Option<i32>,
+    // only with --workflow, one per scanmem command except exit
+    steps: Vec<ScanStep>,
+    // when the first and last match line (as printed by list) arrived, since the first command was sent.
+    // None when scanmem printed no match lines
+    first_match_time: Option<Duration>,
+    last_match_time: Option<Duration>,
+}
+
+/// scanmem commands to run against the synthetic load, one axis of the sweep.
+#[derive(Default, Debug)]
+struct CommandSet {
+    name: String,
+    commands: String,
+    // --expect-matches, or derived from the set's plant-values, None when not checked
+    expect_matches: Option<u64>,
+}
+
 #[derive(Default, Debug)]
 struct BenchmarkResult {
-    // params
+    // params, the coordinates of the scenario in the sweep
+    repeat: usize,
+    command_set: String,
+    nthreads: i32,
     synthetic_load_size: u64, 
     synthetic_load_random_seed: u64,
     
     // timings
     timing: BenchmarkTiming,
 
+    // iterations actually run, more than --iterations when adaptive
+    iterations: usize,
+
+    // per iteration scanmem stats, target_count of them per iteration
+    iteration_stats: Vec<IterationStats>,
+
+    // why the scenario failed, its timings and aggregates are not meaningful when set
+    failure: Option<String>,
+
     // aggregates (in seconds)
     mean: f64,
-    median: f64,
+    median: Option<f64>,
+    min: f64,
+    max: f64,
+    standard_deviation: f64,
+    // standard_deviation / mean
+    coefficient_of_variation: f64,
+    // coefficient_of_variation is above --max-cv
+    unreliable: bool,
+
+    // mean of the lowest thread count of this size, seed and repeat / mean, None if either failed
+    speedup: Option<f64>,
+}
+
+/// One size and thread count (and seed, for the per seed breakdown) aggregated over every repeat that didn't fail.
+#[derive(Default, Debug)]
+struct RepeatAggregate {
+    synthetic_load_size: u64,
+    command_set: String,
+    nthreads: i32,
+    // None when pooled over all seeds
+    seed: Option<u64>,
+    // number of results aggregated, repeats times seeds when pooled
+    repeats: usize,
+
+    // aggregates over every iteration of every repeat (in seconds)
+    mean: f64,
+    median: Option<f64>,
     min: f64,
     max: f64,
     standard_deviation: f64,
 
+    // sample variance of the per-repeat means, the run-to-run drift, and the content sensitivity when pooled over seeds (in seconds squared)
+    between_repeat_variance: f64,
+    // mean of the per-repeat iteration variances (in seconds squared)
+    within_repeat_variance: f64,
+
+    // single thread mean / (mean * nthreads) for the same size, None without a 1 (or -1) thread baseline
+    efficiency: Option<f64>,
+}
+
+/// A size of the sweep that wasn't run.
+#[derive(Default, Debug)]
+struct SkippedSize {
+    synthetic_load_size: u64,
+    reason: String,
+}
+
+/// Ordinary least squares fit of y = intercept + slope * x.
+#[derive(Default, Debug)]
+struct LinearFit {
+    intercept: f64,
+    slope: f64,
+    r_squared: f64,
+}
+
+/// Mean scan time as a function of size, fitted over the per size means of one command set and thread count.
+#[derive(Default, Debug)]
+struct ScanCost {
+    command_set: String,
+    nthreads: i32,
+    // fixed overhead of a scanmem invocation, startup and attach (in seconds)
+    overhead: f64,
+    nanoseconds_per_byte: f64,
+    r_squared: f64,
 }
 
+/// Version of the `BenckmarkReport` layout and of the --csv columns, bump it whenever a field or column is added,
+/// removed or changes meaning. Every --csv row carries it, so tools reading them can tell the layouts apart.
+const REPORT_SCHEMA_VERSION: u32 = 17;
+
 #[derive(Default, Debug)]
 struct BenckmarkReport {
+    // REPORT_SCHEMA_VERSION of the benchmark that wrote the report
+    schema_version: u32,
+
     // metadata
+    // when the run started, in seconds since the Unix epoch
+    started_at: u64,
     scanmem_program: String,
-    scanmem_commands: String,
-    nthreads: i32,
+    variant: String,
+    // first line of `scanmem --version`, None if that failed
+    scanmem_version: Option<String>,
+    command_sets: Vec<CommandSet>,
+    workflow: bool,
+    scanmem_prompt: String,
+    pid_arg_template: String,
+    threads_arg_template: String,
+    nthreads: Vec<i32>,
+    // None when sweeping minbytes to maxbytes
+    sizes: Option<Vec<u64>>,
+    // None when left to the scheduler
+    harness_cpus: Option<Vec<usize>>,
+    scanmem_cpus: Option<Vec<usize>>,
     minbytes: u64,
     maxbytes: u64,
     stepbytes: u64,
     stepfactor: f64,
+    // None when not limited
+    max_size_bytes: Option<u64>,
+    seed: u64,
+    seed_scheme: String,
+    seeds: usize,
+    // bytes per second copied by memcpy, with --measure-bandwidth
+    memcpy_bandwidth: Option<f64>,
+    backend: String,
+    rng: String,
+    target_count: usize,
+    reset_command: Option<String>,
+    drop_caches: bool,
     iterations: usize,
+    // adaptive iteration count, both None when fixed
+    min_time: Option<f64>,
+    target_cv: Option<f64>,
+    max_iterations: usize,
+    // as a percentage, None when not checked
+    max_cv: Option<f64>,
+    repeats: usize,
+    expect_matches: Option<u64>,
     timeout: u64,
 
-    // results
+    // results, every size of every repeat
     results: Vec<BenchmarkResult>,
+    skipped: Vec<SkippedSize>,
+    // pooled over all repeats and seeds of a size
+    repeat_aggregates: Vec<RepeatAggregate>,
+    // per seed, only with more than one seed
+    seed_aggregates: Vec<RepeatAggregate>,
+    // one per thread count with at least three sizes
+    scan_costs: Vec<ScanCost>,
 }
 
-struct ChildProcess {
-    child_process: Child,
-    stdin: BufWriter<ChildStdin>,
-    stdout: BufReader<ChildStdout>,
-    stderr: BufReader<ChildStderr>,
-    echo: bool,
+fn is_scanmem_error(line: &str) -> bool {
+    return line.trim_start().to_ascii_lowercase().starts_with("error");
 }
 
-impl ChildProcess {
-    fn new(command: &str, args: &str, echo: bool) -> Result<ChildProcess, String> {
-        let args_vec: Vec<&str> = args.split_ascii_whitespace().collect();
+/// Match count from scanmem's "info: we currently have N matches." line.
+fn parse_match_count(line: &str) -> Option<u64> {
+    let (_, rest) = line.split_once("we currently have ")?;
+    return rest.split_whitespace().next()?.parse().ok();
+}
 
-        let mut c = match Command::new(command).args(args_vec).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
-            Ok(c) => c,
-            Err(e) => {
-                return Err(e.to_string())    
-            }
-        };
-        let stdin = BufWriter::new(c.stdin.take().unwrap());
-        let stdout = BufReader::new(c.stdout.take().unwrap());
-        let stderr = BufReader::new(c.stderr.take().unwrap());
+fn perform_benchmark_iteration(setup: &ScenarioSetup, scenario: &Scenario, synthetic_load: &mut ChildProcess, sequence: &mut u64) -> Result<IterationStats, String> {
+    
+    // Create scanmem child process
+    log_println!("Starting scanmem child process...");
+    let args = scanmem_args(&setup.pid_arg_template, &setup.threads_arg_template, synthetic_load.child_process.id(), scenario.nthreads);
+    let mut scanmem = ChildProcess::new(&setup.scanmem_program, &args, setup.verbose)?;
+    // set right after spawn, before scanmem starts its scan threads which inherit it
+    if let Some(cpus) = &setup.scanmem_cpus {
+        if let Err(e) = set_affinity(scanmem.child_process.id(), cpus) {
+            log_println!("Warning: not pinning scanmem: {}", e);
+        }
+    }
+    scanmem.capture_output(setup.capture_lines);
+    if let Some(timeout) = setup.timeout {
+        scanmem.kill_after(timeout);
+    }
+    let result = run_scanmem(&mut scanmem, scenario.scanmem_commands, setup.workflow, &setup.scanmem_prompt, synthetic_load, sequence);
+    // whatever run_scanmem made of the terminated scanmem, it is the timeout that matters
+    let result = match scanmem.timed_out() {
+        true => Err(format!("{} after {} s, terminated scanmem pid {}", TIMEOUT_ERROR, setup.timeout.unwrap().as_secs_f64(), scanmem.child_process.id())),
+        false => result,
+    };
+    return result.map_err(|e| {
+        let captured = scanmem.captured_output();
+        if captured.is_empty() {
+            return e;
+        }
+        return format!("{}\nlast scanmem output:\n{}", e, captured.join("\n"));
+    });
+}
 
-        return Ok(ChildProcess{child_process: c, stdin: stdin, stdout: stdout, stderr: stderr, echo: echo})
+/// A match as `list` prints it, like "[ 0]  7f0d1c2e3000,  2 +  1000,  heap, 1, [I32 ]".
+fn is_match_line(line: &str) -> bool {
+    static MATCH_LINE: OnceLock<Regex> = OnceLock::new();
+    return MATCH_LINE.get_or_init(|| Regex::new(r"^\[\s*\d+\]\s+[0-9a-fA-F]+,").unwrap()).is_match(line);
+}
+
+/// How long to wait for the stderr reader to catch up with a prompt scanmem already printed.
+const STDERR_CATCH_UP: Duration = Duration::from_millis(20);
+
+/// Match count scanmem printed on stderr since the last command was sent, from the captured lines. The stderr reader
+/// may not have read it yet when the prompt arrives, so give it `STDERR_CATCH_UP` before giving up.
+fn wait_for_captured_match_count(scanmem: &ChildProcess) -> Option<u64> {
+    let deadline = Instant::now() + STDERR_CATCH_UP;
+    loop {
+        let count = scanmem.captured_output().iter().rev().take_while(|l| !l.starts_with("stdin: ")).find_map(|l| parse_match_count(l));
+        if count.is_some() || Instant::now() >= deadline {
+            return count;
+        }
+        std::thread::sleep(Duration::from_millis(1));
     }
+}
 
-    fn read_until_line(&mut self, condition_line: &str) -> Result<(), String> {
-        loop {
-            let mut buf = String::new();
-            self.stdout.read_line(&mut buf).map_err(|e|e.to_string())?;
-            if self.echo {
-                print!("pid {} stdout: {}", self.child_process.id(), buf);
-            }
-            if buf.eq(format!("{}\n", condition_line).as_str()) {
-                return Ok(())
+fn run_scanmem(scanmem: &mut ChildProcess, scanmem_commands: &[&str], workflow: bool, scanmem_prompt: &str, synthetic_load: &mut ChildProcess, sequence: &mut u64) -> Result<IterationStats, String> {
+    let stderr_reader = scanmem.collect_stderr()?;
+    scanmem.read_until_prompt(scanmem_prompt)?;
+    let mut stdout_match_count = None;
+    let mut steps = vec![];
+    let commands_start = Instant::now();
+    let mut first_match_time = None;
+    let mut last_match_time = None;
+    let mut stderr_wait = Duration::ZERO;
+    for command in scanmem_commands {
+        if let Some(load_command) = command.trim_start().strip_prefix("load:").filter(|_| workflow) {
+            synthetic_load_command(synthetic_load, sequence, load_command.trim())?;
+            continue;
+        }
+        let start = Instant::now();
+        scanmem.write_line(command)?;
+        // there is no prompt after exit
+        if command.trim_ascii() != "exit" {
+            let out = scanmem.read_until_prompt_with(scanmem_prompt, |line| {
+                if is_match_line(line) {
+                    last_match_time = Some(commands_start.elapsed() - stderr_wait);
+                    first_match_time = first_match_time.or(last_match_time);
+                }
+            })?;
+            let time = start.elapsed();
+            let step_match_count = out.lines().rev().find_map(parse_match_count);
+            stdout_match_count = step_match_count.or(stdout_match_count);
+            if workflow {
+                let mut step = ScanStep::default();
+                step.command = command.trim().to_string();
+                step.time = time;
+                let wait_start = Instant::now();
+                step.match_count = step_match_count.or_else(|| wait_for_captured_match_count(scanmem));
+                stderr_wait += wait_start.elapsed();
+                steps.push(step);
             }
         }
     }
+    
+    // Cleanup
+    let (status, rusage) = scanmem.wait_with_rusage()?;
+    let switches = ContextSwitches{voluntary: rusage.ru_nvcsw, involuntary: rusage.ru_nivcsw};
+    log_println!("scanmem child process done ({}, {} voluntary, {} involuntary context switches)", status, switches.voluntary, switches.involuntary);
+
+    // scanmem keeps going after most errors, so a bad command or missing permission only shows up here
+    let stderr_lines = stderr_reader.join().map_err(|_|"stderr reader panicked".to_string())?;
+    if stderr_lines.iter().any(|l| is_scanmem_error(l)) {
+        return Err(format!("scanmem reported errors:\n{}", stderr_lines.join("\n")));
+    }
 
+    // scanmem prints its info messages on stderr, fall back to stdout for builds that don't
+    let mut stats = IterationStats::default();
+    stats.context_switches = switches;
+    stats.exit_code = status.code();
+    stats.exit_signal = status.signal();
+    stats.match_count = stderr_lines.iter().rev().find_map(|l| parse_match_count(l)).or(stdout_match_count);
+    stats.steps = steps;
+    stats.stderr_wait = stderr_wait;
+    stats.first_match_time = first_match_time;
+    stats.last_match_time = last_match_time;
+    
+    return Ok(stats)
+}
 
-    fn write_line(&mut self, line: &str) -> Result<(), String> {
-        let out = format!("{}\n", line);
-        if self.echo {
-            print!("pid {} stdin: {}", self.child_process.id(), out);
+/// Write back dirty pages and drop the page cache, dentries and inodes. Only root may, everyone else gets a single warning.
+fn drop_page_cache() {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    unsafe { libc::sync() };
+    if let Err(e) = std::fs::write("/proc/sys/vm/drop_caches", "3") {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            log_println!("Warning: not dropping the page cache: {}", e);
         }
-        self.stdin.write_all(out.as_bytes()).map_err(|e|e.to_string())?;
-        self.stdin.flush().map_err(|e|e.to_string())?;
-        return Ok(())
     }
 }
 
-impl Drop for ChildProcess {
-    fn drop(&mut self) {
-        if self.echo {
-            // Read whats left in the output pipes
-            loop {
-                let mut buf = String::new();
-                let len = self.stdout.read_line(&mut buf).unwrap();
-                if len == 0 {
-                    break;
-                }
-                print!("pid {} stdout: {}", self.child_process.id(), buf);
+/// Have synthetic_load page out its memory. When nothing could be paged out, there is no swap, warn once.
+fn page_out(synthetic_load: &mut ChildProcess, sequence: &mut u64) -> Result<(), String> {
+    static WARNED: AtomicBool = AtomicBool::new(false);
+    let lines = synthetic_load_command(synthetic_load, sequence, "madvise-pageout")?;
+    if let Some(warning) = lines.iter().find(|l| l.starts_with("Warning: ")) {
+        if !WARNED.swap(true, Ordering::Relaxed) {
+            log_println!("{} (synthetic_load pid {}), scans with --drop-caches do not start cold", warning, synthetic_load.child_process.id());
+        }
+    }
+    return Ok(());
+}
+
+/// Send one command to a `--numbered` synthetic_load and wait for its own "Done #<n>" or "ERR #<n> <message>" marker.
+/// Returns the lines before it, or the message as the error when the command failed.
+fn synthetic_load_command(synthetic_load: &mut ChildProcess, sequence: &mut u64, command: &str) -> Result<Vec<String>, String> {
+    synthetic_load.write_line(command)?;
+    *sequence += 1;
+    let marker = Regex::new(format!(r"^(Done #{0}|ERR #{0}( .*)?)$", sequence).as_str()).unwrap();
+    let (lines, last) = synthetic_load.read_until_matching(&marker)?;
+    if let Some(message) = last.strip_prefix(format!("ERR #{}", sequence).as_str()) {
+        return Err(format!("synthetic_load command \"{}\" failed: {}", command, message.trim_start()));
+    }
+    return Ok(lines);
+}
+
+fn perform_benchmark_scenario(setup: &ScenarioSetup, scenario: &Scenario, run_start: Instant) -> Result<(BenchmarkTiming, Vec<IterationStats>), String> {
+
+    let mut report = BenchmarkTiming::default();
+    let budget = &scenario.budget;
+    let mut iteration_stats = Vec::with_capacity(budget.min_iterations * setup.target_count);
+
+    let total_start_time = Instant::now();
+
+    // Create the synthetic_load child processes and init, each with its "Done #<n>" sequence.
+    // Any still running when this returns early are killed when dropped
+    let mut targets = Vec::with_capacity(setup.target_count);
+    for _ in 0..setup.target_count {
+        log_println!("Starting synthetic_load child process...");
+        let synthetic_load = ChildProcess::new_when_ready(&setup.synthetic_load_program, &[format!("--backend={}", setup.synthetic_load_backend), format!("--rng={}", setup.synthetic_load_rng), "--no-history".to_string(), "--numbered".to_string()], setup.verbose)?;
+        log_println!("Child pid: {}", synthetic_load.child_process.id());
+        targets.push((synthetic_load, 0u64));
+    }
+    report.spawn_time = total_start_time.elapsed();
+
+    let phase_start = Instant::now();
+    for (synthetic_load, sequence) in targets.iter_mut() {
+        synthetic_load_command(synthetic_load, sequence, format!("set-memory-size {}", scenario.synthetic_load_size).as_str())?;
+    }
+    report.allocation_time = phase_start.elapsed();
+
+    let phase_start = Instant::now();
+    for (synthetic_load, sequence) in targets.iter_mut() {
+        synthetic_load_command(synthetic_load, sequence, format!("fill-random {}", scenario.synthetic_load_random_seed).as_str())?;
+    }
+    report.fill_time = phase_start.elapsed();
+    
+    report.setup_time = total_start_time.elapsed();
+
+    report.benchmark_times.reserve(budget.min_iterations);
+    let reset_command = setup.reset_command.as_ref().map(|c| c.replace("{seed}", scenario.synthetic_load_random_seed.to_string().as_str()));
+    let mut iteration = 0;
+    while !enough_iterations(budget, &report.benchmark_times) {
+        for (synthetic_load, sequence) in targets.iter_mut() {
+            if let Some(command) = reset_command.as_deref().filter(|_| iteration > 0) {
+                synthetic_load_command(synthetic_load, sequence, command)?;
+            }
+            if setup.drop_caches {
+                page_out(synthetic_load, sequence)?;
             }
-            loop {
-                let mut buf = String::new();
-                let len = self.stderr.read_line(&mut buf).unwrap();
-                if len == 0 {
-                    break;
+        }
+        if setup.drop_caches {
+            drop_page_cache();
+        }
+        // scanmem attaches to a single pid, so scan the targets one after the other and sum the times
+        report.start_offsets.push(run_start.elapsed());
+        let mut iteration_time = Duration::ZERO;
+        for (target, (synthetic_load, sequence)) in targets.iter_mut().enumerate() {
+            let checksum_before = match setup.check_integrity {
+                true => Some(synthetic_load_command(synthetic_load, sequence, "checksum")?),
+                false => None,
+            };
+            let start = Instant::now();
+            let mut stats = perform_benchmark_iteration(setup, scenario, synthetic_load, sequence)?;
+            stats.target = target;
+            stats.time = start.elapsed() - stats.stderr_wait;
+            if let Some(before) = checksum_before {
+                let after = synthetic_load_command(synthetic_load, sequence, "checksum")?;
+                if after != before {
+                    return Err(format!("iteration {} changed synthetic_load's memory ({} before, {} after)", iteration, before.join(" "), after.join(" ")));
                 }
-                print!("pid {} stderr: {}", self.child_process.id(), buf);
             }
+            iteration_time += stats.time;
+            iteration_stats.push(stats);
         }
-        println!("Dropping ChildProcess pid {}", self.child_process.id());
+        report.benchmark_times.push(iteration_time);
+        iteration += 1;
+    }
+
+    // Shut down in this order: every scanmem has been reaped by now (run_scanmem waits for it), then make sure the
+    // kernel has dropped the ptrace attachment and let it settle, and only then tell synthetic_load to exit. Exiting
+    // while still traced can leave it to be reaped late, overlapping the next scenario.
+    for (synthetic_load, _) in targets.iter_mut() {
+        wait_for_detach(synthetic_load.child_process.id())?;
     }
+    std::thread::sleep(setup.detach_settle);
+    for (synthetic_load, _) in targets.iter_mut() {
+        synthetic_load.write_line("exit")?;
+        match setup.timeout {
+            Some(timeout) => synthetic_load.wait_or_kill(timeout).map(|_| ()).map_err(|e| format!("synthetic_load exit: {}", e))?,
+            None => synthetic_load.child_process.wait().map(|_| ()).map_err(|e|e.to_string())?,
+        }
+    }
+
+    report.total_time = total_start_time.elapsed();
+
+    return Ok((report, iteration_stats))
 }
 
-fn perform_benchmark_iteration(scanmem_program: &str, scanmem_commands: &Vec<&str>, target_process_pid: u32, nthreads: i32, verbose: bool) -> Result<(), String> {
-    
-    // Create scanmem child process
-    println!("Starting scanmem child process...");
-    let args: String;
-    if nthreads == -1 {
-        args = format!("--pid={}", target_process_pid);
+/// How long the kernel gets to drop scanmem's ptrace attachment after scanmem has exited.
+const DETACH_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// The pid tracing `pid`, from the TracerPid line of /proc/<pid>/status, 0 when not traced.
+fn tracer_pid(pid: u32) -> Result<u32, String> {
+    let status = std::fs::read_to_string(format!("/proc/{}/status", pid)).map_err(|e|e.to_string())?;
+    let line = status.lines().find_map(|line| line.strip_prefix("TracerPid:")).ok_or("no TracerPid in status".to_string())?;
+    return line.trim().parse().map_err(|e: std::num::ParseIntError|e.to_string());
+}
+
+/// Wait until nothing traces `pid` any more, for at most `DETACH_TIMEOUT`.
+fn wait_for_detach(pid: u32) -> Result<(), String> {
+    let start = Instant::now();
+    loop {
+        let tracer = tracer_pid(pid).map_err(|e| format!("pid {}: {}", pid, e))?;
+        if tracer == 0 {
+            return Ok(());
+        }
+        if start.elapsed() > DETACH_TIMEOUT {
+            return Err(format!("pid {} still traced by pid {} after scanmem exited", pid, tracer));
+        }
+        std::thread::sleep(Duration::from_millis(1));
     }
-    else {
-        args = format!("--pid={} -j={}", target_process_pid, nthreads);
+}
+
+/// Whether `times` satisfy the budget: the minimum count, then min_time and target_cv until max_iterations.
+fn enough_iterations(budget: &IterationBudget, times: &[Duration]) -> bool {
+    if times.len() < budget.min_iterations {
+        return false;
     }
-    let mut scanmem = ChildProcess::new(scanmem_program, args.as_str(), verbose)?;
-    for command in scanmem_commands {
-        scanmem.write_line(command)?;
+    if budget.min_time.is_none() && budget.target_cv.is_none() {
+        return true;
     }
-    
-    // Cleanup
-    scanmem.child_process.wait().unwrap();
-    println!("scanmem child process done");
-    
-    return Ok(())
+    if times.len() >= budget.max_iterations {
+        return true;
+    }
+    let total = times.iter().sum::<Duration>();
+    if budget.min_time.is_some_and(|min_time| total < min_time) {
+        return false;
+    }
+    if let Some(target_cv) = budget.target_cv {
+        // the standard deviation needs two
+        if times.len() < 2 {
+            return false;
+        }
+        let mean = total.as_secs_f64() / times.len() as f64;
+        let cv = compute_standard_deviation(times.iter().map(|t| t.as_secs_f64()), mean) / mean;
+        return cv <= target_cv;
+    }
+    return true;
 }
 
-fn perform_benchmark_scenario(scanmem_program: &str, scanmem_commands: &Vec<&str>, synthetic_load_program: &str, synthetic_load_size: u64, synthetic_load_random_seed: u64, iterations: usize, nthreads: i32, verbose: bool) -> Result<BenchmarkTiming, String> {
+/// Read a --command-set-file.
+fn read_command_set_file(path: &str) -> Result<Vec<CommandSet>, String> {
+    let content = std::fs::read_to_string(path).map_err(|e| format!("failed to read {}: {}", path, e))?;
+    let mut library: Vec<CommandSet> = vec![];
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, commands) = line.split_once('=').ok_or(format!("{}:{}: not NAME = COMMANDS", path, number + 1))?;
+        let name = name.trim();
+        let commands = commands.trim();
+        let commands = commands.strip_prefix('"').and_then(|c| c.strip_suffix('"')).unwrap_or(commands);
+        if library.iter().any(|s| s.name == name) {
+            return Err(format!("{}:{}: command set \"{}\" defined twice", path, number + 1, name));
+        }
+        let mut set = CommandSet::default();
+        set.name = name.to_string();
+        set.commands = commands.to_string();
+        library.push(set);
+    }
+    return Ok(library);
+}
 
-    let mut report = BenchmarkTiming::default();
+/// The --scanmem-commands set (as "default") followed by the --command-set ones, each NAME=COMMANDS or the NAME of a
+/// set in `library`, names must be unique.
+fn parse_command_sets(scanmem_commands: Option<String>, command_sets: &[String], library: &[CommandSet]) -> Result<Vec<CommandSet>, String> {
+    let mut sets = vec![];
+    if let Some(commands) = scanmem_commands {
+        let mut set = CommandSet::default();
+        set.name = "default".to_string();
+        set.commands = commands;
+        sets.push(set);
+    }
+    for arg in command_sets {
+        let (name, commands) = match arg.split_once('=') {
+            Some((name, commands)) => (name.trim(), commands),
+            None => {
+                let name = arg.trim();
+                let set = library.iter().find(|s| s.name == name).ok_or(format!("command set \"{}\" is not NAME=COMMANDS or a name in --command-set-file", arg))?;
+                (name, set.commands.as_str())
+            }
+        };
+        if sets.iter().any(|s: &CommandSet| s.name == name) {
+            return Err(format!("command set \"{}\" given twice", name));
+        }
+        let mut set = CommandSet::default();
+        set.name = name.to_string();
+        set.commands = commands.to_string();
+        sets.push(set);
+    }
+    return Ok(sets);
+}
 
-    let total_start_time = SystemTime::now();
+/// Count of the only "load: plant-values <type> <value> <count> <seed>" of a workflow, None without or with several.
+fn planted_count(commands: &str) -> Option<u64> {
+    let counts: Vec<Option<u64>> = commands.split(';').filter_map(|command| command.trim_start().strip_prefix("load:")).map(|load| load.split_whitespace().collect::<Vec<_>>()).filter(|args| args.first() == Some(&"plant-values")).map(|args| args.get(3)?.parse().ok()).collect();
+    return match counts.as_slice() {
+        [count] => *count,
+        _ => None,
+    };
+}
 
-    // Create synthetic_load child process and init
-    println!("Starting synthetic_load child process...");
-    let mut synthetic_load = ChildProcess::new(synthetic_load_program, "", verbose)?;
-    println!("Child pid: {}", synthetic_load.child_process.id());
-    synthetic_load.write_line(format!("set-memory-size {}", synthetic_load_size).as_str())?;
-    synthetic_load.read_until_line("Done")?;
-    synthetic_load.write_line(format!("fill-random {}", synthetic_load_random_seed).as_str())?;
-    synthetic_load.read_until_line("Done")?;
+/// The sizes to run, --sizes or the --minbytes to --maxbytes sweep.
+fn sweep_sizes(report: &BenckmarkReport) -> Vec<u64> {
+    if let Some(sizes) = &report.sizes {
+        return sizes.clone();
+    }
+    let mut sizes = vec![];
+    let mut step_size = report.minbytes;
+    while step_size >= report.minbytes && step_size <= report.maxbytes {
+        sizes.push(step_size);
+        step_size += report.stepbytes;
+        step_size = ((step_size as f64) * report.stepfactor) as u64;
+    }
+    return sizes;
+}
 
-    
-    report.setup_time = SystemTime::now().duration_since(total_start_time).map_err(|e|e.to_string())?;
+/// Why `size` doesn't fit under --max-size-bytes, None if it does.
+fn exceeds_memory(report: &BenckmarkReport, size: u64) -> Option<String> {
+    // one synthetic load per target, and scanmem's match list for the one being scanned
+    let needed = size.saturating_mul(report.target_count as u64 + 1);
+    let max = report.max_size_bytes.filter(|max| needed > *max)?;
+    return Some(format!("would exceed memory, needs about {:#x} bytes of {:#x}", needed, max));
+}
 
-    report.benchmark_times.reserve(iterations);
-    for _ in 0..iterations {
-        let start = SystemTime::now();
-        perform_benchmark_iteration(scanmem_program, &scanmem_commands, synthetic_load.child_process.id(), nthreads, verbose)?;
-        report.benchmark_times.push(SystemTime::now().duration_since(start).map_err(|e|e.to_string())?)
-    }
+/// What the sweep would run, for --dry-run.
+fn render_matrix(report: &BenckmarkReport, sizes: &[u64]) -> String {
+    let mut out = String::new();
+    let runnable: Vec<u64> = sizes.iter().copied().filter(|size| exceeds_memory(report, *size).is_none()).collect();
+    let sizes_text: Vec<String> = sizes.iter().map(|size| if runnable.contains(size) { format!("{:#x}", size) } else { format!("{:#x} (skipped)", size) }).collect();
+    let names: Vec<&str> = report.command_sets.iter().map(|set| set.name.as_str()).collect();
+    writeln!(out, "sizes: {}", sizes_text.join(", ")).unwrap();
+    writeln!(out, "command sets: {}", names.join(", ")).unwrap();
+    writeln!(out, "threads: {:?}", report.nthreads).unwrap();
+    writeln!(out, "seeds: {}, repeats: {}", report.seeds, report.repeats).unwrap();
+    let scenarios = runnable.len() * report.command_sets.len() * report.nthreads.len() * report.seeds * report.repeats;
+    writeln!(out, "{} scenarios of {} iterations ({} scanmem runs each)", scenarios, report.iterations, report.iterations * report.target_count).unwrap();
+    return out;
+}
 
-    synthetic_load.write_line(format!("exit").as_str())?;
-    synthetic_load.child_process.wait().unwrap();
+/// Largest size --dry-run calibrates at, so estimating a sweep of several GB doesn't take as long as running part of it.
+const CALIBRATION_MAX_SIZE: u64 = 0x4000000;
 
-    report.total_time = SystemTime::now().duration_since(total_start_time).map_err(|e|e.to_string())?;
+/// (fixed, per byte) cost through the (size, seconds) calibration points. One point is taken as all per byte, two are
+/// fitted exactly, neither cost below zero.
+fn calibration_cost(points: &[(u64, f64)]) -> (f64, f64) {
+    let (size, time) = points[0];
+    let Some(&(larger_size, larger_time)) = points.get(1) else {
+        return (0.0, time / size as f64);
+    };
+    let per_byte = ((larger_time - time) / (larger_size - size) as f64).max(0.0);
+    return ((time - per_byte * size as f64).max(0.0), per_byte);
+}
 
-    return Ok(report)
+/// Rough total run time of the sweep from one calibration iteration at each of the `calibrations` sizes, smallest
+/// first. Starting synthetic_load is taken as fixed, the setup and the iterations as a fixed plus a per byte cost, which
+/// keeps the scanmem startup that dominates small sizes from being scaled up. Thread counts and command sets are
+/// assumed to cost the same.
+fn render_estimate(report: &BenckmarkReport, sizes: &[u64], budget: &IterationBudget, calibrations: &[(u64, BenchmarkTiming)]) -> String {
+    let iteration_points: Vec<(u64, f64)> = calibrations.iter().map(|(size, timing)| (*size, timing.benchmark_times.first().copied().unwrap_or_default().as_secs_f64())).collect();
+    let setup_points: Vec<(u64, f64)> = calibrations.iter().map(|(size, timing)| (*size, (timing.setup_time - timing.spawn_time).as_secs_f64())).collect();
+    let (iteration_fixed, iteration_per_byte) = calibration_cost(&iteration_points);
+    let (setup_fixed, setup_per_byte) = calibration_cost(&setup_points);
+    let spawn = calibrations.iter().map(|(_, timing)| timing.spawn_time.as_secs_f64()).sum::<f64>() / calibrations.len() as f64;
+    let scenarios_per_size = (report.command_sets.len() * report.nthreads.len() * report.seeds * report.repeats) as f64;
+    let mut total = 0.0;
+    for &size in sizes.iter().filter(|size| exceeds_memory(report, **size).is_none()) {
+        let iteration = iteration_fixed + iteration_per_byte * size as f64;
+        let mut iterations = budget.min_iterations;
+        if let Some(min_time) = budget.min_time {
+            let needed = (min_time.as_secs_f64() / iteration).ceil() as usize;
+            iterations = iterations.max(needed.min(budget.max_iterations));
+        }
+        total += scenarios_per_size * (spawn + setup_fixed + setup_per_byte * size as f64 + iterations as f64 * iteration);
+    }
+    // with --target-cv the iteration count isn't known up front
+    let bound = if budget.target_cv.is_some() { "at least " } else { "" };
+    let method = match calibrations {
+        [(size, _)] => format!("extrapolated linearly by size from one iteration at {:#x}", size),
+        _ => format!("fixed plus per byte cost fitted to one iteration each at {}", calibrations.iter().map(|(size, _)| format!("{:#x}", size)).collect::<Vec<_>>().join(" and ")),
+    };
+    return format!("estimated run time: {}{:.0} s (rough, {})", bound, total.ceil(), method);
 }
 
 fn parse_scanmem_commands(input: &str) -> Vec<&str> {
@@ -225,75 +956,733 @@ fn parse_scanmem_commands(input: &str) -> Vec<&str> {
     // check if last command is 'exit'
     if let Some(last) = ret.last() {
         if !last.trim_ascii().eq("exit") {
-            println!("Warning: scanmem commands does not exit with 'exit'!.");
+            log_println!("Warning: scanmem commands does not exit with 'exit'!.");
         }
     }
     return ret;
 }
 
-fn compute_median<I>(values: I) -> f64 where I: Iterator<Item = f64>, {
+fn format_median(median: Option<f64>) -> String {
+    return match median {
+        Some(m) => format!("{:.6}", m),
+        None => "-".to_string(),
+    };
+}
+
+/// As a percentage, flagged with "!" below 50% where adding threads mostly stopped paying off.
+fn format_efficiency(efficiency: Option<f64>) -> String {
+    return match efficiency {
+        Some(e) if e < 0.5 => format!("{:.1}% !", e * 100.0),
+        Some(e) => format!("{:.1}%", e * 100.0),
+        None => "-".to_string(),
+    };
+}
+
+/// The narrowing of a workflow, each step's median time and the match count of the last iteration.
+fn format_steps(iteration_stats: &[IterationStats]) -> String {
+    let Some(last) = iteration_stats.last() else {
+        return "-".to_string();
+    };
+    let steps: Vec<String> = last.steps.iter().enumerate().map(|(i, step)| {
+        let median = compute_median(iteration_stats.iter().filter_map(|s| s.steps.get(i)).map(|s| s.time.as_secs_f64()));
+        let matches = step.match_count.map(|m| m.to_string()).unwrap_or("?".to_string());
+        return format!("\"{}\" {} s {} matches", step.command, format_median(median), matches);
+    }).collect();
+    return steps.join(" -> ");
+}
+
+fn format_speedup(speedup: Option<f64>) -> String {
+    return match speedup {
+        Some(s) => format!("{:.2}x", s),
+        None => "-".to_string(),
+    };
+}
+
+/// The coordinates of a scenario in the sweep, like "repeat 0, size 0x1000000, seed 0x1, commands default, 4 threads".
+fn scenario_label(result: &BenchmarkResult) -> String {
+    return format!("repeat {}, size {:#x}, seed {:#x}, commands {}, {} threads", result.repeat, result.synthetic_load_size, result.synthetic_load_random_seed, result.command_set, result.nthreads);
+}
+
+/// Summary table of the report, one row per scenario followed by the per size aggregates when there are several repeats.
+fn render_table(report: &BenckmarkReport) -> String {
+    let mut out = String::new();
+    writeln!(out, "scanmem: {} (threads: {:?})", report.scanmem_program, report.nthreads).unwrap();
+    writeln!(out, "variant: {}, version: {}", report.variant, report.scanmem_version.as_deref().unwrap_or("unknown")).unwrap();
+    for set in &report.command_sets {
+        let name = if report.command_sets.len() > 1 { format!(" ({})", set.name) } else { String::new() };
+        writeln!(out, "commands{}: {}{}", name, set.commands, if report.workflow { " (workflow)" } else { "" }).unwrap();
+    }
+    if let Some(reset_command) = &report.reset_command {
+        writeln!(out, "reset: {}", reset_command).unwrap();
+    }
+    if report.drop_caches {
+        writeln!(out, "drop caches: madvise-pageout before every iteration").unwrap();
+    }
+    if report.target_count > 1 {
+        writeln!(out, "targets: {} synthetic_load processes, scanned one after the other", report.target_count).unwrap();
+    }
+    writeln!(out, "backend: {}, rng: {}, seed: {:#x} ({}, {} per size), iterations: {}, repeats: {}", report.backend, report.rng, report.seed, report.seed_scheme, report.seeds, report.iterations, report.repeats).unwrap();
+    if let Some(bandwidth) = report.memcpy_bandwidth {
+        writeln!(out, "memcpy bandwidth: {:.3} GB/s", bandwidth / 1e9).unwrap();
+    }
+    if report.min_time.is_some() || report.target_cv.is_some() {
+        let min_time = report.min_time.map(|t| format!("{} s", t)).unwrap_or("-".to_string());
+        let target_cv = report.target_cv.map(|cv| format!("{}%", cv)).unwrap_or("-".to_string());
+        writeln!(out, "adaptive: min time {}, target cv {}, up to {} iterations", min_time, target_cv, report.max_iterations).unwrap();
+    }
+    writeln!(out).unwrap();
+
+    // the command set column only when there is more than one
+    let sets = report.command_sets.len() > 1;
+    let set_column = |name: &str| if sets { format!("{:>12} ", name) } else { String::new() };
+
+    // times in seconds
+    write!(out, "{:>6} {:>12} {}", "repeat", "size", set_column("commands")).unwrap();
+    writeln!(out, "{:>7} {:>18} {:>6} {:>10} {:>10} {:>10} {:>10} {:>10} {:>8}", "threads", "seed", "iters", "mean", "median", "min", "max", "stddev", "speedup").unwrap();
+    for result in &report.results {
+        write!(out, "{:>6} {:>#12x} {}{:>7} {:>#18x} ", result.repeat, result.synthetic_load_size, set_column(&result.command_set), result.nthreads, result.synthetic_load_random_seed).unwrap();
+        match &result.failure {
+            Some(failure) => writeln!(out, "FAILED: {}", failure.lines().next().unwrap_or("")).unwrap(),
+            None => {
+                write!(out, "{:>6} {:>10.6} {:>10} {:>10.6} {:>10.6} {:>10.6} {:>8}", result.iterations, result.mean, format_median(result.median), result.min, result.max, result.standard_deviation, format_speedup(result.speedup)).unwrap();
+                if result.unreliable {
+                    write!(out, " unreliable, cv {:.1}%", result.coefficient_of_variation * 100.0).unwrap();
+                }
+                writeln!(out).unwrap();
+            },
+        }
+    }
+
+    if report.seeds > 1 {
+        writeln!(out).unwrap();
+        writeln!(out, "per seed, over repeats:").unwrap();
+        writeln!(out, "{:>12} {}{:>7} {:>18} {:>7} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12}", "size", set_column("commands"), "threads", "seed", "repeats", "mean", "median", "min", "max", "stddev", "var between", "var within").unwrap();
+        for aggregate in &report.seed_aggregates {
+            writeln!(out, "{:>#12x} {}{:>7} {:>#18x} {:>7} {:>10.6} {:>10} {:>10.6} {:>10.6} {:>10.6} {:>12.3e} {:>12.3e}", aggregate.synthetic_load_size, set_column(&aggregate.command_set), aggregate.nthreads, aggregate.seed.unwrap_or(0), aggregate.repeats, aggregate.mean, format_median(aggregate.median), aggregate.min, aggregate.max, aggregate.standard_deviation, aggregate.between_repeat_variance, aggregate.within_repeat_variance).unwrap();
+        }
+    }
+
+    if report.repeats > 1 || report.nthreads.len() > 1 || report.seeds > 1 || sets {
+        writeln!(out).unwrap();
+        if report.seeds > 1 {
+            writeln!(out, "pooled over seeds and repeats:").unwrap();
+        }
+        writeln!(out, "{:>12} {}{:>7} {:>7} {:>10} {:>10} {:>10} {:>10} {:>10} {:>12} {:>12} {:>11}", "size", set_column("commands"), "threads", "repeats", "mean", "median", "min", "max", "stddev", "var between", "var within", "efficiency").unwrap();
+        for aggregate in &report.repeat_aggregates {
+            writeln!(out, "{:>#12x} {}{:>7} {:>7} {:>10.6} {:>10} {:>10.6} {:>10.6} {:>10.6} {:>12.3e} {:>12.3e} {:>11}", aggregate.synthetic_load_size, set_column(&aggregate.command_set), aggregate.nthreads, aggregate.repeats, aggregate.mean, format_median(aggregate.median), aggregate.min, aggregate.max, aggregate.standard_deviation, aggregate.between_repeat_variance, aggregate.within_repeat_variance, format_efficiency(aggregate.efficiency)).unwrap();
+        }
+    }
+
+    if report.target_count > 1 {
+        writeln!(out).unwrap();
+        for result in report.results.iter().filter(|r| r.failure.is_none()) {
+            let target_means: Vec<String> = (0..report.target_count).map(|target| {
+                let times: Vec<f64> = result.iteration_stats.iter().filter(|s| s.target == target).map(|s| s.time.as_secs_f64()).collect();
+                return format!("#{} {:.6} s", target, times.iter().sum::<f64>() / times.len() as f64);
+            }).collect();
+            writeln!(out, "targets ({}), mean: {}", scenario_label(result), target_means.join(", ")).unwrap();
+        }
+    }
+
+    if report.workflow {
+        writeln!(out).unwrap();
+        for result in report.results.iter().filter(|r| r.failure.is_none()) {
+            writeln!(out, "steps ({}): {}", scenario_label(result), format_steps(&result.iteration_stats)).unwrap();
+        }
+    }
+
+    for skipped in &report.skipped {
+        writeln!(out, "{:>6} {:>#12x} skipped: {}", "-", skipped.synthetic_load_size, skipped.reason).unwrap();
+    }
+
+    // only when the commands list matches, scanmem doesn't print them while scanning
+    let listed: Vec<&BenchmarkResult> = report.results.iter().filter(|r| r.iteration_stats.iter().any(|s| s.first_match_time.is_some())).collect();
+    if !listed.is_empty() {
+        writeln!(out).unwrap();
+    }
+    for result in listed {
+        let first = compute_median(result.iteration_stats.iter().filter_map(|s| s.first_match_time).map(|t| t.as_secs_f64()));
+        let last = compute_median(result.iteration_stats.iter().filter_map(|s| s.last_match_time).map(|t| t.as_secs_f64()));
+        writeln!(out, "matches listed ({}): first after {} s, last after {} s (medians)", scenario_label(result), format_median(first), format_median(last)).unwrap();
+    }
+
+    // including scanmem's startup, the scan cost lines below leave it out
+    if report.memcpy_bandwidth.is_some() {
+        writeln!(out).unwrap();
+        for aggregate in &report.repeat_aggregates {
+            writeln!(out, "throughput (size {:#x}, commands {}, {} threads): {}", aggregate.synthetic_load_size, aggregate.command_set, aggregate.nthreads, format_throughput(aggregate.synthetic_load_size as f64 / aggregate.mean, report.memcpy_bandwidth)).unwrap();
+        }
+    }
+
+    // where the time of the whole run went
+    let timings = || report.results.iter().map(|r| &r.timing);
+    let secs = |f: fn(&BenchmarkTiming) -> Duration| timings().map(f).sum::<Duration>().as_secs_f64();
+    let scanning: f64 = timings().flat_map(|t| t.benchmark_times.iter()).sum::<Duration>().as_secs_f64();
+    writeln!(out).unwrap();
+    writeln!(out, "setup: {:.3} s (spawn {:.3} s, allocation {:.3} s, fill {:.3} s), scanning: {:.3} s, total: {:.3} s", secs(|t| t.setup_time), secs(|t| t.spawn_time), secs(|t| t.allocation_time), secs(|t| t.fill_time), scanning, secs(|t| t.total_time)).unwrap();
+
+    writeln!(out).unwrap();
+    if report.scan_costs.is_empty() {
+        writeln!(out, "scan cost: needs at least three sizes").unwrap();
+    }
+    for cost in &report.scan_costs {
+        writeln!(out, "scan cost (commands {}, {} threads): {:.6} ns/byte, {}, overhead: {:.6} s, R²: {:.4}", cost.command_set, cost.nthreads, cost.nanoseconds_per_byte, format_throughput(1e9 / cost.nanoseconds_per_byte, report.memcpy_bandwidth), cost.overhead, cost.r_squared).unwrap();
+    }
+    return out;
+}
+
+/// Quote a CSV field if it needs it.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    return field.to_string();
+}
+
+/// The pooled aggregates as a wide CSV table, one row per cell of the sweep. Times in seconds.
+fn render_csv(report: &BenckmarkReport) -> String {
+    let mut out = String::new();
+    writeln!(out, "schema_version,started_at,variant,scanmem_program_version,size,command_set,threads,repeats,mean,median,min,max,stddev,var_between,var_within,efficiency").unwrap();
+    let variant = csv_field(&report.variant);
+    let version = csv_field(report.scanmem_version.as_deref().unwrap_or(""));
+    for aggregate in &report.repeat_aggregates {
+        let median = aggregate.median.map(|m| m.to_string()).unwrap_or_default();
+        let efficiency = aggregate.efficiency.map(|e| e.to_string()).unwrap_or_default();
+        writeln!(out, "{},{},{},{},{},{},{},{},{},{},{},{},{},{},{},{}", report.schema_version, report.started_at, variant, version, aggregate.synthetic_load_size, csv_field(&aggregate.command_set), aggregate.nthreads, aggregate.repeats, aggregate.mean, median, aggregate.min, aggregate.max, aggregate.standard_deviation, aggregate.between_repeat_variance, aggregate.within_repeat_variance, efficiency).unwrap();
+    }
+    return out;
+}
+
+/// Render the report in one of the `--format` formats.
+fn render(report: &BenckmarkReport, format: &str) -> String {
+    return match format {
+        "debug" => format!("{:?}\n", report),
+        _ => render_table(report),
+    };
+}
+
+/// splitmix64 finalizer, spelled out so derived seeds stay the same across Rust versions (unlike `DefaultHasher`).
+fn mix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    return x ^ (x >> 31);
+}
+
+/// Fill seed `index` (of --seeds) for `size`, depends only on the master seed, the size and the index, not on what
+/// other sizes are run.
+fn derive_seed(seed_scheme: &str, master_seed: u64, size: u64, index: usize) -> u64 {
+    let seed = match seed_scheme {
+        "per-size" => mix64(master_seed ^ mix64(size)),
+        _ => master_seed,
+    };
+    if index == 0 {
+        return seed;
+    }
+    return mix64(seed ^ mix64(index as u64));
+}
+
+/// None for empty input.
+fn compute_median<I>(values: I) -> Option<f64> where I: Iterator<Item = f64>, {
     let mut data: Vec<f64> = values.collect();
+    if data.is_empty() {
+        return None;
+    }
     data.sort_by(|a,b|a.total_cmp(b));
-    return data[data.len() / 2];
+    return Some(data[data.len() / 2]);
 }
 
+/// Sample standard deviation (divides by n - 1). Defined as 0.0 for fewer than two values, where it is undefined.
 fn compute_standard_deviation<I>(values: I, mean: f64) -> f64 where I: Iterator<Item = f64>, {
     let data: Vec<f64> = values.collect();
     let len = data.len();
-    let sum = data.into_iter().reduce(|acc: f64, e: f64| acc + (e - mean)).unwrap();
-    return f64::sqrt(1.0f64 / len as f64 * sum.powi(2));
+    if len < 2 {
+        return 0.0;
+    }
+    let sum: f64 = data.into_iter().map(|e| (e - mean).powi(2)).sum();
+    return f64::sqrt(sum / (len - 1) as f64);
+}
+
+/// None with fewer than three points or when all x are equal. R² is 1.0 when y is constant, the fit is exact then.
+fn fit_linear(points: &[(f64, f64)]) -> Option<LinearFit> {
+    if points.len() < 3 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_x = points.iter().map(|p| p.0).sum::<f64>() / n;
+    let mean_y = points.iter().map(|p| p.1).sum::<f64>() / n;
+    let sxx: f64 = points.iter().map(|p| (p.0 - mean_x).powi(2)).sum();
+    let sxy: f64 = points.iter().map(|p| (p.0 - mean_x) * (p.1 - mean_y)).sum();
+    if sxx == 0.0 {
+        return None;
+    }
+
+    let mut fit = LinearFit::default();
+    fit.slope = sxy / sxx;
+    fit.intercept = mean_y - fit.slope * mean_x;
+    let ss_res: f64 = points.iter().map(|p| (p.1 - (fit.intercept + fit.slope * p.0)).powi(2)).sum();
+    let ss_tot: f64 = points.iter().map(|p| (p.1 - mean_y).powi(2)).sum();
+    fit.r_squared = if ss_tot == 0.0 { 1.0 } else { 1.0 - ss_res / ss_tot };
+    return Some(fit);
+}
+
+fn estimate_scan_cost(aggregates: &[RepeatAggregate], command_set: &str, nthreads: i32) -> Option<ScanCost> {
+    let points: Vec<(f64, f64)> = aggregates.iter().filter(|a| a.command_set == command_set && a.nthreads == nthreads).map(|a| (a.synthetic_load_size as f64, a.mean)).collect();
+    let fit = fit_linear(&points)?;
+    let mut cost = ScanCost::default();
+    cost.command_set = command_set.to_string();
+    cost.nthreads = nthreads;
+    cost.overhead = fit.intercept;
+    cost.nanoseconds_per_byte = fit.slope * 1e9;
+    cost.r_squared = fit.r_squared;
+    return Some(cost);
+}
+
+/// Aggregate the successful results of each size, command set and thread count across repeats, in that order.
+fn aggregate_repeats(results: &[BenchmarkResult], per_seed: bool) -> Vec<RepeatAggregate> {
+    let mut by_scenario: BTreeMap<(u64, &str, i32, Option<u64>), Vec<&BenchmarkResult>> = BTreeMap::new();
+    for result in results.iter().filter(|r| r.failure.is_none()) {
+        let seed = per_seed.then_some(result.synthetic_load_random_seed);
+        by_scenario.entry((result.synthetic_load_size, result.command_set.as_str(), result.nthreads, seed)).or_default().push(result);
+    }
+
+    let mut aggregates = vec![];
+    for ((size, command_set, nthreads, seed), repeats) in by_scenario {
+        let times: Vec<f64> = repeats.iter().flat_map(|r| r.timing.benchmark_times.iter().map(|e|e.as_secs_f64())).collect();
+        let repeat_means: Vec<f64> = repeats.iter().map(|r| r.mean).collect();
+        let repeat_means_mean = repeat_means.iter().sum::<f64>() / repeat_means.len() as f64;
+
+        let mut aggregate = RepeatAggregate::default();
+        aggregate.synthetic_load_size = size;
+        aggregate.command_set = command_set.to_string();
+        aggregate.nthreads = nthreads;
+        aggregate.seed = seed;
+        aggregate.repeats = repeats.len();
+        aggregate.max = times.iter().copied().max_by(|a,b|a.total_cmp(b)).unwrap_or(f64::NAN);
+        aggregate.min = times.iter().copied().min_by(|a,b|a.total_cmp(b)).unwrap_or(f64::NAN);
+        aggregate.mean = times.iter().sum::<f64>() / times.len() as f64;
+        aggregate.standard_deviation = compute_standard_deviation(times.iter().copied(), aggregate.mean);
+        aggregate.median = compute_median(times.iter().copied());
+        aggregate.between_repeat_variance = compute_standard_deviation(repeat_means.into_iter(), repeat_means_mean).powi(2);
+        aggregate.within_repeat_variance = repeats.iter().map(|r| r.standard_deviation.powi(2)).sum::<f64>() / repeats.len() as f64;
+        aggregates.push(aggregate);
+    }
+    return aggregates;
+}
+
+/// Fill in the parallel efficiency of every aggregate against the single thread aggregate of the same size and command set.
+fn compute_efficiency(aggregates: &mut [RepeatAggregate]) {
+    // -1 is scanmem without threading, which is single threaded too
+    let baselines: BTreeMap<(u64, String), f64> = aggregates.iter().filter(|a| a.nthreads == 1 || a.nthreads == -1).map(|a| ((a.synthetic_load_size, a.command_set.clone()), a.mean)).collect();
+    for aggregate in aggregates.iter_mut() {
+        let threads = aggregate.nthreads.max(1) as f64;
+        aggregate.efficiency = baselines.get(&(aggregate.synthetic_load_size, aggregate.command_set.clone())).map(|baseline| baseline / (aggregate.mean * threads));
+    }
+}
+
+// large enough to not fit in any cache
+const BANDWIDTH_BUFFER_SIZE: usize = 0x400_0000;
+const BANDWIDTH_ROUNDS: usize = 5;
+
+/// Bytes per second memcpy copies between two large buffers, the best of `BANDWIDTH_ROUNDS` copies.
+fn measure_memcpy_bandwidth() -> f64 {
+    let src = vec![0x5au8; BANDWIDTH_BUFFER_SIZE];
+    let mut dst = vec![0u8; BANDWIDTH_BUFFER_SIZE];
+    let mut best = Duration::MAX;
+    for _ in 0..BANDWIDTH_ROUNDS {
+        let start = Instant::now();
+        dst.copy_from_slice(std::hint::black_box(&src));
+        std::hint::black_box(&mut dst);
+        best = best.min(start.elapsed());
+    }
+    return BANDWIDTH_BUFFER_SIZE as f64 / best.as_secs_f64();
+}
+
+/// As "<GB/s> GB/s", followed by the percentage of the memcpy bandwidth when it was measured.
+fn format_throughput(bytes_per_second: f64, memcpy_bandwidth: Option<f64>) -> String {
+    let mut out = format!("{:.3} GB/s", bytes_per_second / 1e9);
+    if let Some(bandwidth) = memcpy_bandwidth {
+        out += &format!(" ({:.1}% of memcpy)", bytes_per_second / bandwidth * 100.0);
+    }
+    return out;
+}
+
+/// MemAvailable of /proc/meminfo in bytes.
+fn available_memory() -> Result<u64, String> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").map_err(|e| format!("failed to read /proc/meminfo: {}", e))?;
+    let kib = meminfo.lines().find_map(|l| l.strip_prefix("MemAvailable:")).and_then(|v| v.trim().trim_end_matches("kB").trim().parse::<u64>().ok());
+    return kib.map(|kib| kib * 1024).ok_or("no MemAvailable in /proc/meminfo".to_string());
+}
+
+/// First line `program --version` prints. stdin is closed so a program that doesn't know the flag and starts
+/// its prompt instead exits right away.
+fn program_version(program: &str) -> Result<String, String> {
+    let output = std::process::Command::new(program).arg("--version").stdin(std::process::Stdio::null()).output().map_err(|e| format!("failed to run {} --version: {}", program, e))?;
+    if !output.status.success() {
+        return Err(format!("{} --version failed: {}", program, output.status));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    return stdout.lines().map(str::trim).find(|l| !l.is_empty()).map(str::to_string).ok_or(format!("{} --version printed nothing", program));
+}
+
+/// Pin this process (and so synthetic_load) to `harness_cpus` and work out the CPUs scanmem gets, returning both.
+/// Only bad CPU lists are errors, failing to pin is a warning and leaves the harness unpinned.
+fn pin_harness(harness_cpus: Option<&str>, cpu_affinity: Option<&str>) -> Result<(Option<CpuSet>, Option<CpuSet>), String> {
+    let harness_cpus = harness_cpus.map(parse_cpu_list).transpose()?;
+    let mut scanmem_cpus = cpu_affinity.map(parse_cpu_list).transpose()?;
+    let Some(harness) = harness_cpus else {
+        return Ok((None, scanmem_cpus));
+    };
+
+    // scanmem would inherit the harness pinning, give it everything else we may run on instead
+    let all_cpus = get_affinity(0);
+    if let Err(e) = set_affinity(0, &harness) {
+        log_println!("Warning: not pinning the harness: {}", e);
+        return Ok((None, scanmem_cpus));
+    }
+    if scanmem_cpus.is_none() {
+        scanmem_cpus = match all_cpus {
+            Ok(all) => Some(all.difference(&harness).copied().collect::<CpuSet>()).filter(|cpus| !cpus.is_empty()),
+            Err(e) => {
+                log_println!("Warning: {}", e);
+                None
+            }
+        };
+        if scanmem_cpus.is_none() {
+            log_println!("Warning: no CPUs left for scanmem outside --harness-cpus, it shares them with the harness");
+        }
+    }
+    if let Some(cpus) = &scanmem_cpus {
+        if !cpus.is_disjoint(&harness) {
+            log_println!("Warning: --cpu-affinity overlaps --harness-cpus");
+        }
+    }
+    return Ok((Some(harness), scanmem_cpus));
 }
 
 fn main() -> ExitCode {
 
+    let run_start = Instant::now();
     let cli = Cli::parse();
+    if let Some(path) = &cli.log_file {
+        if let Err(e) = set_log_file(path) {
+            println!("Failed to open log file {}", e);
+            return ExitCode::from(EXIT_FAILURE)
+        }
+    }
+    if cli.timestamps {
+        enable_echo_timestamps(run_start);
+    }
+
+    let (harness_cpus, scanmem_cpus) = match pin_harness(cli.harness_cpus.as_deref(), cli.cpu_affinity.as_deref()) {
+        Ok(cpus) => cpus,
+        Err(e) => {
+            log_println!("{}", e);
+            return ExitCode::from(EXIT_FAILURE)
+        }
+    };
 
-    let synthetic_load_path = std::env::current_exe().unwrap().parent().unwrap().to_path_buf().join(SYNTHETIC_LOAD_NAME);
+    // fail before any setup work instead of with a spawn error deep in the first scenario
+    let synthetic_load_path = cli.synthetic_load_program.clone().map(std::path::PathBuf::from).unwrap_or_else(default_synthetic_load_path);
+    let synthetic_load_path = match find_program(synthetic_load_path.to_str().unwrap()) {
+        Some(path) => path,
+        None => {
+            log_println!("synthetic_load not found at {}", synthetic_load_path.display());
+            return ExitCode::from(EXIT_FAILURE)
+        }
+    };
+    if find_program(&cli.scanmem_program).is_none() {
+        log_println!("scanmem not found at {}", cli.scanmem_program);
+        return ExitCode::from(EXIT_FAILURE)
+    }
     
     
     let mut report = BenckmarkReport::default();
+    report.schema_version = REPORT_SCHEMA_VERSION;
+    report.started_at = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    report.variant = cli.variant.unwrap_or_else(|| std::path::Path::new(&cli.scanmem_program).file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default());
     report.scanmem_program = cli.scanmem_program;
-    report.scanmem_commands = cli.scanmem_commands;
+    report.scanmem_version = match program_version(&report.scanmem_program) {
+        Ok(version) => Some(version),
+        Err(e) => {
+            log_println!("Warning: {}", e);
+            None
+        }
+    };
+    let library = match cli.command_set_file.as_deref().map(read_command_set_file).transpose() {
+        Ok(library) => library.unwrap_or_default(),
+        Err(e) => {
+            log_println!("{}", e);
+            return ExitCode::from(EXIT_FAILURE)
+        }
+    };
+    report.command_sets = match parse_command_sets(cli.scanmem_commands, &cli.command_set, &library) {
+        Ok(sets) => sets,
+        Err(e) => {
+            log_println!("{}", e);
+            return ExitCode::from(EXIT_FAILURE)
+        }
+    };
+    report.workflow = cli.workflow;
+    for set in &mut report.command_sets {
+        set.expect_matches = cli.expect_matches.or_else(|| planted_count(&set.commands).filter(|_| report.workflow));
+    }
+    report.scanmem_prompt = cli.scanmem_prompt;
+    report.pid_arg_template = cli.pid_arg_template;
+    report.threads_arg_template = cli.threads_arg_template;
     report.nthreads = cli.nthreads;
+    report.sizes = cli.sizes;
+    report.harness_cpus = harness_cpus.map(|cpus| cpus.into_iter().collect());
+    report.scanmem_cpus = scanmem_cpus.as_ref().map(|cpus| cpus.iter().copied().collect());
     report.minbytes = cli.minbytes;
     report.maxbytes = cli.maxbytes;
     report.stepbytes = cli.stepbytes;
     report.stepfactor = cli.stepfactor;
+    report.max_size_bytes = match cli.max_size_bytes {
+        Some(0) => None,
+        Some(max) => Some(max),
+        None => match available_memory() {
+            Ok(available) => Some(available / 4 * 3),
+            Err(e) => {
+                log_println!("Warning: not limiting sizes to the available memory: {}", e);
+                None
+            }
+        },
+    };
+    report.seed = cli.seed;
+    report.seed_scheme = cli.seed_scheme;
+    report.seeds = cli.seeds;
+    report.backend = cli.backend;
+    report.rng = cli.rng;
+    report.target_count = cli.target_count as usize;
     report.iterations = cli.iterations;
+    report.min_time = cli.min_time;
+    report.target_cv = cli.target_cv;
+    report.max_iterations = cli.max_iterations;
+    report.max_cv = cli.max_cv;
+    report.repeats = cli.repeats;
+    report.expect_matches = cli.expect_matches;
+    report.reset_command = cli.reset_command;
+    report.drop_caches = cli.drop_caches;
     report.timeout = cli.timeout;
 
-    let scanmem_commands = parse_scanmem_commands(&report.scanmem_commands);
+    let command_sets: Vec<(&str, Vec<&str>)> = report.command_sets.iter().map(|set| (set.name.as_str(), parse_scanmem_commands(&set.commands))).collect();
+    let sizes = sweep_sizes(&report);
 
-    let mut step_size = report.minbytes;
-    while step_size >= report.minbytes && step_size <= report.maxbytes {
+    let mut budget = IterationBudget::default();
+    budget.min_iterations = cli.iterations;
+    budget.max_iterations = cli.max_iterations.max(cli.iterations);
+    budget.min_time = cli.min_time.map(Duration::from_secs_f64);
+    budget.target_cv = cli.target_cv.map(|pct| pct / 100.0);
+
+    let mut setup = ScenarioSetup::default();
+    setup.scanmem_program = report.scanmem_program.clone();
+    setup.workflow = report.workflow;
+    setup.scanmem_prompt = report.scanmem_prompt.clone();
+    setup.pid_arg_template = report.pid_arg_template.clone();
+    setup.threads_arg_template = report.threads_arg_template.clone();
+    setup.scanmem_cpus = scanmem_cpus;
+    setup.capture_lines = cli.capture_lines;
+    setup.synthetic_load_program = synthetic_load_path.to_str().unwrap().to_string();
+    setup.synthetic_load_backend = report.backend.clone();
+    setup.synthetic_load_rng = report.rng.clone();
+    setup.target_count = report.target_count;
+    setup.reset_command = report.reset_command.clone();
+    setup.drop_caches = cli.drop_caches;
+    setup.check_integrity = cli.check_integrity;
+    setup.detach_settle = Duration::from_millis(cli.detach_settle_ms);
+    setup.timeout = (cli.timeout > 0).then(|| Duration::from_secs(cli.timeout));
+    setup.verbose = cli.verbose;
+
+    if cli.dry_run {
+        log_print!("{}", render_matrix(&report, &sizes));
+        // calibrate with one iteration of the first scenario at the smallest size, and at a larger one to tell the fixed
+        // cost from the per byte cost
+        let runnable: Vec<u64> = sizes.iter().copied().filter(|size| exceeds_memory(&report, *size).is_none()).collect();
+        let smallest = runnable.iter().copied().min();
+        let larger = runnable.iter().copied().filter(|size| Some(*size) > smallest && *size <= CALIBRATION_MAX_SIZE).max();
+        if let (Some(smallest), Some((_, scanmem_commands)), Some(&nthreads)) = (smallest, command_sets.first(), report.nthreads.first()) {
+            let mut calibrations = vec![];
+            for size in std::iter::once(smallest).chain(larger) {
+                log_println!("Calibrating with one iteration at size {:#x}...", size);
+                let mut calibration = Scenario::default();
+                calibration.scanmem_commands = scanmem_commands;
+                calibration.nthreads = nthreads;
+                calibration.synthetic_load_size = size;
+                calibration.synthetic_load_random_seed = derive_seed(&report.seed_scheme, report.seed, size, 0);
+                calibration.budget.min_iterations = 1;
+                calibration.budget.max_iterations = 1;
+                match perform_benchmark_scenario(&setup, &calibration, run_start) {
+                    Ok((timing, _)) => calibrations.push((size, timing)),
+                    Err(e) => {
+                        log_println!("Calibration failed, no run time estimate: {}", e);
+                        calibrations.clear();
+                        break;
+                    }
+                }
+            }
+            if !calibrations.is_empty() {
+                log_println!("{}", render_estimate(&report, &sizes, &budget, &calibrations));
+            }
+        }
+        return ExitCode::from(EXIT_SUCCESS)
+    }
+
+    if cli.measure_bandwidth {
+        log_println!("Measuring memcpy bandwidth...");
+        report.memcpy_bandwidth = Some(measure_memcpy_bandwidth());
+    }
+
+    for repeat in 0..report.repeats {
+        for &step_size in &sizes {
+            if let Some(reason) = exceeds_memory(&report, step_size) {
+                if repeat == 0 {
+                    let mut skipped = SkippedSize::default();
+                    skipped.synthetic_load_size = step_size;
+                    skipped.reason = reason;
+                    log_println!("Skipping size {:#x}: {}", step_size, skipped.reason);
+                    report.skipped.push(skipped);
+                }
+                continue;
+            }
         
-        let mut benchmark_result = BenchmarkResult::default();
-        benchmark_result.synthetic_load_size = step_size;
-        benchmark_result.synthetic_load_random_seed = 0x1; 
-
-        match perform_benchmark_scenario(&report.scanmem_program, &scanmem_commands, synthetic_load_path.to_str().unwrap(), benchmark_result.synthetic_load_size, benchmark_result.synthetic_load_random_seed, cli.iterations, report.nthreads, cli.verbose) {
-            Ok(t) => benchmark_result.timing = t,
-            Err(err) => {
-                println!("Benchmark failed: {}", err);
+            for (command_set, scanmem_commands) in &command_sets {
+                for seed_index in 0..report.seeds {
+                    let first_of_size = report.results.len();
+                    for nthreads in report.nthreads.clone() {
+                        let mut benchmark_result = BenchmarkResult::default();
+                        benchmark_result.repeat = repeat;
+                        benchmark_result.command_set = command_set.to_string();
+                        benchmark_result.nthreads = nthreads;
+                        benchmark_result.synthetic_load_size = step_size;
+                        benchmark_result.synthetic_load_random_seed = derive_seed(&report.seed_scheme, report.seed, step_size, seed_index);
+
+                        let mut scenario = Scenario::default();
+                        scenario.scanmem_commands = scanmem_commands;
+                        scenario.nthreads = nthreads;
+                        scenario.synthetic_load_size = benchmark_result.synthetic_load_size;
+                        scenario.synthetic_load_random_seed = benchmark_result.synthetic_load_random_seed;
+                        scenario.budget = budget;
+                        match perform_benchmark_scenario(&setup, &scenario, run_start) {
+                            Ok((t, stats)) => {
+                                benchmark_result.iterations = t.benchmark_times.len();
+                                benchmark_result.timing = t;
+                                benchmark_result.iteration_stats = stats;
+                            },
+                            Err(err) => {
+                                log_println!("Benchmark failed: {}", err);
+                                benchmark_result.failure = Some(err);
+                            }
+                        }
+
+                        // compute aggregates
+                        if benchmark_result.failure.is_none() {
+                            benchmark_result.max = benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()).max_by(|a,b|a.total_cmp(b)).unwrap_or(f64::NAN);
+                            benchmark_result.min = benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()).min_by(|a,b|a.total_cmp(b)).unwrap_or(f64::NAN);
+                            benchmark_result.mean = benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()).sum::<f64>() / benchmark_result.timing.benchmark_times.len() as f64;
+                            benchmark_result.standard_deviation = compute_standard_deviation(benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()), benchmark_result.mean);
+                            benchmark_result.median = compute_median(benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()));
+                            benchmark_result.coefficient_of_variation = benchmark_result.standard_deviation / benchmark_result.mean;
+                            benchmark_result.unreliable = report.max_cv.is_some_and(|max_cv| benchmark_result.coefficient_of_variation * 100.0 > max_cv);
+                        }
+
+                        report.results.push(benchmark_result);
+                    }
+
+                    let size_results = &mut report.results[first_of_size..];
+                    let baseline = size_results.iter().min_by_key(|r| r.nthreads).filter(|r| r.failure.is_none()).map(|r| r.mean);
+                    for result in size_results.iter_mut() {
+                        if result.failure.is_none() {
+                            result.speedup = baseline.map(|b| b / result.mean);
+                        }
+                    }
+                }
             }
         }
+    }
 
-        // compute aggregates
-        benchmark_result.max = benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()).max_by(|a,b|a.total_cmp(b)).unwrap();
-        benchmark_result.min = benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()).min_by(|a,b|a.total_cmp(b)).unwrap();
-        benchmark_result.mean = benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()).sum::<f64>() / benchmark_result.timing.benchmark_times.len() as f64;
-        benchmark_result.standard_deviation = compute_standard_deviation(benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()), benchmark_result.mean);
-        benchmark_result.median = compute_median(benchmark_result.timing.benchmark_times.iter().map(|e|e.as_secs_f64()));
+    report.repeat_aggregates = aggregate_repeats(&report.results, false);
+    if report.seeds > 1 {
+        report.seed_aggregates = aggregate_repeats(&report.results, true);
+    }
+    compute_efficiency(&mut report.repeat_aggregates);
+    report.scan_costs = report.command_sets.iter().flat_map(|set| report.nthreads.iter().map(move |nthreads| (set.name.as_str(), *nthreads))).filter_map(|(command_set, nthreads)| estimate_scan_cost(&report.repeat_aggregates, command_set, nthreads)).collect();
 
-        report.results.push(benchmark_result);
+    log_print!("{}", render(&report, &cli.format));
+    if let Some(path) = &cli.csv {
+        if let Err(e) = std::fs::write(path, render_csv(&report)) {
+            log_println!("Failed to write {}: {}", path, e);
+            return ExitCode::from(EXIT_FAILURE)
+        }
+    }
 
-        // next step
-        step_size += report.stepbytes;
-        step_size = ((step_size as f64) * report.stepfactor) as u64;
+    // not a failure, the numbers are there but too noisy to quote
+    for result in report.results.iter().filter(|r| r.unreliable) {
+        log_println!("Warning: scenario ({}) is unreliable, cv {:.1}% is above {}%", scenario_label(result), result.coefficient_of_variation * 100.0, report.max_cv.unwrap());
+    }
+
+    let failed: Vec<&BenchmarkResult> = report.results.iter().filter(|r| r.failure.is_some()).collect();
+    for result in &failed {
+        log_println!("Scenario ({}) failed: {}", scenario_label(result), result.failure.as_ref().unwrap());
+    }
+    if !failed.is_empty() {
+        return ExitCode::from(EXIT_FAILURE)
+    }
+
+    let mut mismatched = false;
+    for result in &report.results {
+        let Some(expected) = report.command_sets.iter().find(|set| set.name == result.command_set).and_then(|set| set.expect_matches) else {
+            continue;
+        };
+        for (i, stats) in result.iteration_stats.iter().enumerate() {
+            if stats.match_count != Some(expected) {
+                log_println!("Scenario ({}) iteration {} target {}: expected {} matches, scanmem reported {:?}", scenario_label(result), i / report.target_count, stats.target, expected, stats.match_count);
+                mismatched = true;
+            }
+        }
     }
+    if mismatched {
+        return ExitCode::from(EXIT_MATCH_MISMATCH)
+    }
+
+    return ExitCode::from(EXIT_SUCCESS)
+}
 
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    println!("{:?}", report);
+    #[test]
+    fn calibration_cost_separates_fixed_and_per_byte() {
+        assert_eq!(calibration_cost(&[(0x1000, 2.0), (0x3000, 4.0)]), (1.0, 1.0 / 0x1000 as f64));
+        assert_eq!(calibration_cost(&[(0x1000, 2.0)]), (0.0, 2.0 / 0x1000 as f64));
+        // noise making the larger size faster doesn't give a negative per byte cost
+        assert_eq!(calibration_cost(&[(0x1000, 2.0), (0x2000, 1.5)]), (2.0, 0.0));
+    }
+
+    #[test]
+    fn planted_count_of_the_only_plant_values() {
+        assert_eq!(planted_count("load: plant-values u32 0x1234 16 1; = 0x1234; exit"), Some(16));
+        assert_eq!(planted_count("= 1; exit"), None);
+        assert_eq!(planted_count("load: plant-values u8 1 2 1; load: plant-values u8 1 3 1; = 1; exit"), None);
+    }
 
-    return ExitCode::SUCCESS
+    #[test]
+    fn median_of_nothing_is_none() {
+        assert_eq!(compute_median(std::iter::empty()), None);
+    }
+
+    #[test]
+    fn standard_deviation_of_fewer_than_two_is_zero() {
+        assert_eq!(compute_standard_deviation(std::iter::empty(), 0.0), 0.0);
+        assert_eq!(compute_standard_deviation([3.0].into_iter(), 3.0), 0.0);
+    }
+
+    #[test]
+    fn standard_deviation_is_the_sample_one() {
+        let values = [2.0, 4.0, 4.0, 4.0, 5.0, 5.0, 7.0, 9.0];
+        // squared deviations from the mean of 5 sum to 32, over n - 1 = 7
+        let expected = (32.0f64 / 7.0).sqrt();
+        assert!((compute_standard_deviation(values.into_iter(), 5.0) - expected).abs() < 1e-12);
+    }
 }
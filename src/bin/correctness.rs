@@ -0,0 +1,230 @@
+use std::{collections::BTreeSet, process::ExitCode};
+use clap::Parser;
+use clap_num::maybe_hex;
+use scanmem_tests::child_process::ChildProcess;
+use scanmem_tests::{default_synthetic_load_path, find_program, scanmem_args, DEFAULT_PID_ARG_TEMPLATE, DEFAULT_THREADS_ARG_TEMPLATE};
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Cli {
+    /// Path to scanmem program to run.
+    #[arg(long)]
+    scanmem_program: String,
+
+    /// Prompt scanmem prints when it is ready for the next command, matched against the end of its output.
+    #[arg(long, default_value = "> ")]
+    scanmem_prompt: String,
+
+    /// How the scanmem program is told the pid to attach to, as in the benchmark's --pid-arg-template.
+    #[arg(long, default_value = DEFAULT_PID_ARG_TEMPLATE, allow_hyphen_values = true)]
+    pid_arg_template: String,
+
+    /// How the scanmem program is told the thread count, as in the benchmark's --threads-arg-template.
+    #[arg(long, default_value = DEFAULT_THREADS_ARG_TEMPLATE, allow_hyphen_values = true)]
+    threads_arg_template: String,
+
+    /// Size of synthetic load memory (in bytes).
+    #[arg(long, default_value_t = 0x1_000_000u64, value_parser=maybe_hex::<u64>)]
+    size: u64,
+
+    /// Integer type of the planted values, also used as scanmem scan_data_type.
+    #[arg(long, default_value = "u32", value_parser = ["i8", "u8", "i16", "u16", "i32", "u32", "i64", "u64"])]
+    value_type: String,
+
+    /// Value to plant and search for, at most the largest value of --value-type.
+    #[arg(long, default_value_t = 0x12345678u64, value_parser=maybe_hex::<u64>)]
+    value: u64,
+
+    /// Number of values to plant.
+    #[arg(long, default_value_t = 16)]
+    count: usize,
+
+    /// Seed deciding where the values are planted.
+    #[arg(long, default_value_t = 0x1u64, value_parser=maybe_hex::<u64>)]
+    seed: u64,
+
+    /// Number of threads scanmem will use to scan, set to -1 if multi threading is not supported by the scanmem program.
+    #[arg(short = 't', long, default_value_t = -1)]
+    nthreads: i32,
+
+    /// Verbose output.
+    #[arg(short, long, default_value_t = false)]
+    verbose: bool,
+}
+
+/// Send one command to synthetic_load and return the lines it printed before "Done".
+fn synthetic_load_command(synthetic_load: &mut ChildProcess, command: &str) -> Result<Vec<String>, String> {
+    synthetic_load.write_line(command)?;
+    return synthetic_load.read_lines_until("Done");
+}
+
+/// Find the value of the first "`key`: value" line.
+fn find_value<'a>(lines: &'a [String], key: &str) -> Result<&'a str, String> {
+    let prefix = format!("{}: ", key);
+    return lines.iter().find_map(|l| l.strip_prefix(prefix.as_str())).ok_or(format!("\"{}\" missing from: {:?}", key, lines));
+}
+
+fn parse_hex(s: &str) -> Result<u64, String> {
+    return u64::from_str_radix(s.trim_start_matches("0x"), 16).map_err(|e| format!("bad address {}: {}", s, e));
+}
+
+/// Parse the addresses out of scanmem `list` lines, like "[ 0] 7ffd3c5f3a4c,  5 +  1c4c,  stack, 42, [I32 ]".
+fn parse_scanmem_list(lines: &[String]) -> Result<Vec<u64>, String> {
+    let mut addresses = vec![];
+    for line in lines {
+        let Some(rest) = line.strip_prefix('[').and_then(|l| l.split_once(']')).map(|(_, r)| r) else {
+            continue;
+        };
+        let address = rest.split(',').next().unwrap().trim();
+        addresses.push(parse_hex(address)?);
+    }
+    return Ok(addresses);
+}
+
+/// Largest value of `value_type`, like "i16" or "u32".
+fn max_value(value_type: &str) -> u64 {
+    let bits: u32 = value_type[1..].parse().unwrap();
+    let value_bits = match value_type.starts_with('i') {
+        true => bits - 1,
+        false => bits,
+    };
+    return u64::MAX >> (64 - value_bits);
+}
+
+fn scan_data_type(value_type: &str) -> String {
+    return format!("int{}", &value_type[1..]);
+}
+
+/// Plant values in synthetic_load, return (planted addresses, memory range).
+fn plant(synthetic_load: &mut ChildProcess, cli: &Cli) -> Result<(BTreeSet<u64>, std::ops::Range<u64>), String> {
+    synthetic_load_command(synthetic_load, format!("set-memory-size {}", cli.size).as_str())?;
+    synthetic_load_command(synthetic_load, "fill 0")?;
+    let lines = synthetic_load_command(synthetic_load, format!("plant-values {} {} {} {}", cli.value_type, cli.value, cli.count, cli.seed).as_str())?;
+    if !lines.contains(&format!("planted {} values", cli.count)) {
+        return Err(format!("plant-values failed: {:?}", lines));
+    }
+
+    let lines = synthetic_load_command(synthetic_load, "query-planted")?;
+    let planted = lines.iter().filter_map(|l| l.strip_prefix("planted address: ")).map(parse_hex).collect::<Result<BTreeSet<u64>, String>>()?;
+    if planted.len() != cli.count {
+        return Err(format!("expected {} planted addresses, got {}", cli.count, planted.len()));
+    }
+
+    let lines = synthetic_load_command(synthetic_load, "info")?;
+    let start = parse_hex(find_value(&lines, "memory start")?)?;
+    let end = parse_hex(find_value(&lines, "memory end")?)?;
+
+    return Ok((planted, start..end));
+}
+
+/// Run one scanmem search for `--value` against `target_process_pid` and return every address it lists.
+fn scan(cli: &Cli, target_process_pid: u32) -> Result<Vec<u64>, String> {
+    println!("Starting scanmem child process...");
+    let args = scanmem_args(&cli.pid_arg_template, &cli.threads_arg_template, target_process_pid, cli.nthreads);
+    let mut scanmem = ChildProcess::new(&cli.scanmem_program, &args, cli.verbose)?;
+    // drained so a chatty scanmem can't block on a full stderr pipe
+    let stderr_reader = scanmem.collect_stderr()?;
+    scanmem.read_until_prompt(&cli.scanmem_prompt)?;
+    scanmem.write_line(format!("option scan_data_type {}", scan_data_type(&cli.value_type)).as_str())?;
+    scanmem.read_until_prompt(&cli.scanmem_prompt)?;
+    scanmem.write_line(format!("= {}", cli.value).as_str())?;
+    scanmem.read_until_prompt(&cli.scanmem_prompt)?;
+    scanmem.write_line("list")?;
+    let list = scanmem.read_until_prompt(&cli.scanmem_prompt)?;
+    scanmem.write_line("exit")?;
+    let (status, _) = scanmem.wait_with_rusage()?;
+    let stderr_lines = stderr_reader.join().map_err(|_|"stderr reader panicked".to_string())?;
+    println!("scanmem child process done ({})", status);
+    if !status.success() {
+        return Err(format!("scanmem {}:\n{}", status, stderr_lines.join("\n")));
+    }
+
+    let lines: Vec<String> = list.lines().map(|l| l.to_string()).collect();
+    return parse_scanmem_list(&lines);
+}
+
+fn main() -> ExitCode {
+
+    let cli = Cli::parse();
+
+    // synthetic_load would truncate it to the type, and scanmem would search for something else
+    if cli.value > max_value(&cli.value_type) {
+        println!("--value {:#x} does not fit {}, which holds at most {:#x}", cli.value, cli.value_type, max_value(&cli.value_type));
+        return ExitCode::FAILURE
+    }
+
+    let synthetic_load_path = match find_program(default_synthetic_load_path().to_str().unwrap()) {
+        Some(path) => path,
+        None => {
+            println!("synthetic_load not found at {}", default_synthetic_load_path().display());
+            return ExitCode::FAILURE
+        }
+    };
+
+    println!("Starting synthetic_load child process...");
+    let mut synthetic_load = match ChildProcess::new(synthetic_load_path.to_str().unwrap(), &["--no-history".to_string()], cli.verbose) {
+        Ok(c) => c,
+        Err(e) => {
+            println!("Failed to start synthetic_load: {}", e);
+            return ExitCode::FAILURE
+        }
+    };
+    println!("Child pid: {}", synthetic_load.child_process.id());
+
+    let (planted, memory_range) = match plant(&mut synthetic_load, &cli) {
+        Ok(p) => p,
+        Err(e) => {
+            println!("Planting values failed: {}", e);
+            return ExitCode::FAILURE
+        }
+    };
+
+    let found = match scan(&cli, synthetic_load.child_process.id()) {
+        Ok(f) => f,
+        Err(e) => {
+            println!("Scan failed: {}", e);
+            return ExitCode::FAILURE
+        }
+    };
+
+    // the value can legitimately show up elsewhere in the process (stack, parser buffers), only judge the planted memory
+    let outside = found.iter().filter(|a| !memory_range.contains(a)).count();
+    let found: Vec<u64> = found.into_iter().filter(|a| memory_range.contains(a)).collect();
+
+    // collecting into a set would hide an address listed more than once, which is a wrong result too
+    let mut duplicates = BTreeSet::new();
+    let mut unique = BTreeSet::new();
+    for address in &found {
+        if !unique.insert(*address) {
+            duplicates.insert(*address);
+        }
+    }
+    let found = unique;
+
+    if let Err(e) = synthetic_load.write_line("exit").and_then(|_| synthetic_load.child_process.wait().map_err(|e|e.to_string())) {
+        println!("Stopping synthetic_load failed: {}", e);
+        return ExitCode::FAILURE
+    }
+
+    let missed: Vec<&u64> = planted.difference(&found).collect();
+    let spurious: Vec<&u64> = found.difference(&planted).collect();
+
+    println!("planted: {}, found: {}, ignored outside memory: {}", planted.len(), found.len(), outside);
+    for address in &missed {
+        println!("missed: {:#x}", address);
+    }
+    for address in &spurious {
+        println!("spurious: {:#x}", address);
+    }
+    for address in &duplicates {
+        println!("duplicate: {:#x}", address);
+    }
+
+    if !missed.is_empty() || !spurious.is_empty() || !duplicates.is_empty() {
+        println!("FAIL: {} missed, {} spurious, {} duplicate", missed.len(), spurious.len(), duplicates.len());
+        return ExitCode::FAILURE
+    }
+
+    println!("PASS");
+    return ExitCode::SUCCESS
+}
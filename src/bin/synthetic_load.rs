@@ -1,9 +1,112 @@
 
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
 use clap_num::maybe_hex;
+use rustyline::completion::Completer;
 use rustyline::error::ReadlineError;
-use rustyline::{DefaultEditor, Result};
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Context, Editor, Helper, Result};
 use rand::{Rng, SeedableRng};
+use rayon::prelude::*;
+use serde_json::json;
+use std::alloc::Layout;
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::ptr::NonNull;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+// Process arguments, the REPL commands themselves are parsed per line by `Cli`.
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Generator fill-random and verify seed use. The other random commands always use pcg.
+    #[arg(long, value_enum, default_value_t = RngKind::Pcg)]
+    rng: RngKind,
+
+    /// Byte order used when encoding values in typed commands.
+    #[arg(long, value_enum, default_value_t = Endian::Native)]
+    endian: Endian,
+
+    /// How set-memory-size allocates the region, mmap gives distinct anonymous pages that are released on shrink.
+    #[arg(long, value_enum, default_value_t = Backend::Heap)]
+    backend: Backend,
+
+    /// Map the memory at 0x100000000000 (implies --backend mmap) and print the layout on startup, so scanmem
+    /// scripts can use hardcoded addresses. set-memory-size then resizes it in place, and fails if the pages after
+    /// it are taken instead of moving it. Commands that replace the mapping (alloc-lazy, set-memory-size-aligned,
+    /// mmap-fixed) still move it.
+    #[arg(long, default_value_t = false)]
+    deterministic_layout: bool,
+
+    /// File the command history is loaded from and saved to [default: ~/.synthetic_load_history].
+    #[arg(long)]
+    history_file: Option<std::path::PathBuf>,
+
+    /// Don't load or save the command history.
+    #[arg(long, default_value_t = false, conflicts_with = "history_file")]
+    no_history: bool,
+
+    /// Print info and maps as JSON, and the completion marker as a JSON line, for parsing by scripts.
+    #[arg(long, default_value_t = false)]
+    json: bool,
+
+    /// No prompt, and end the output of every command with a single "OK" or "ERR <message>" line.
+    #[arg(short, long, default_value_t = false, conflicts_with = "json")]
+    quiet: bool,
+
+    /// End every command with "Done #<n>", or "ERR #<n> <message>" instead of printing the error when it failed, n
+    /// counting the commands read from stdin starting at 1, so a script can tell which command finished and whether
+    /// it succeeded. Lines that fail to parse are counted and answered too.
+    #[arg(long, default_value_t = false, conflicts_with_all = ["json", "quiet"])]
+    numbered: bool,
+
+    /// Keep the heap backend's allocation when set-memory-size shrinks. Later grows up to the old size then
+    /// reuse it without reallocating, at the cost of holding on to the memory.
+    #[arg(long, default_value_t = false)]
+    keep_capacity: bool,
+
+    /// Allocate all memory from this NUMA node only. Ignored with a warning on single node systems.
+    #[arg(long)]
+    numa_node: Option<usize>,
+
+    /// Also accept commands on a Unix socket at this path, one per line, while stdin is in use.
+    /// The socket gets the output of each command followed by an "OK" or "ERR <message>" line.
+    #[arg(long)]
+    control_socket: Option<std::path::PathBuf>,
+
+    /// Write a byte to this inherited file descriptor and close it once ready to accept commands, for scripts
+    /// that would rather block on a pipe than wait for output. Without it, readiness is only the first "Done".
+    #[arg(long)]
+    ready_fd: Option<i32>,
+
+    #[command(subcommand)]
+    mode: Option<Mode>,
+}
+
+#[derive(Subcommand)]
+enum Mode {
+    /// Perform the given commands in order and exit, instead of starting the interactive prompt.
+    /// Example: synthetic_load run "set-memory-size 0x1000000" "fill-random 1" "info"
+    ///
+    /// "loop <count> [<start> [<step>]]" up to the next "endloop" performs the commands in between count times,
+    /// with every "{i}" in them replaced by start + n * step in hex, for n from 0 to count - 1 (start and step
+    /// default to 0 and 1). Loops don't nest, and the loop and endloop lines get no "Done". The script is checked
+    /// before anything is performed, and fails if start + n * step overflows 64 bits. The first failing command
+    /// ends the script, and synthetic_load exits with status 1.
+    /// Example, with --deterministic-layout:
+    /// run "set-memory-size 0x10000" "loop 16 0x100000000000 0x1000" "set-address {i} 0xff" "endloop"
+    Run {
+        commands: Vec<String>
+    }
+}
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -12,7 +115,39 @@ struct Cli {
     command: Commands,
 }
 
-#[derive(Subcommand, PartialEq, Eq, Debug)]
+/// Like `maybe_hex` but also accepts a leading '-'. Only the low 64 bits are
+/// ever used, so values are returned wide enough to hold any i64 or u64.
+fn maybe_hex_signed(s: &str) -> std::result::Result<i128, String> {
+    match s.strip_prefix('-') {
+        Some(rest) => maybe_hex::<u64>(rest).map(|v| -(v as i128)),
+        None => maybe_hex::<u64>(s).map(|v| v as i128),
+    }
+}
+
+/// Parse a protection in the /proc/pid/maps style, e.g. "rw", "r-x" or "none".
+fn parse_prot(s: &str) -> std::result::Result<i32, String> {
+    if s == "none" {
+        return Ok(libc::PROT_NONE);
+    }
+    let mut prot = libc::PROT_NONE;
+    for c in s.chars() {
+        match c {
+            'r' => prot |= libc::PROT_READ,
+            'w' => prot |= libc::PROT_WRITE,
+            'x' => prot |= libc::PROT_EXEC,
+            '-' => {},
+            _ => return Err(format!("invalid protection character '{}', expected any of \"rwx-\" or \"none\"", c)),
+        }
+    }
+    return Ok(prot);
+}
+
+fn prot_to_string(prot: i32) -> String {
+    let flag = |bit: i32, c: char| if prot & bit != 0 { c } else { '-' };
+    return format!("{}{}{}", flag(libc::PROT_READ, 'r'), flag(libc::PROT_WRITE, 'w'), flag(libc::PROT_EXEC, 'x'));
+}
+
+#[derive(Subcommand, PartialEq, Debug)]
 enum Commands {
     #[clap(alias = "q")]
     Exit,
@@ -20,6 +155,24 @@ enum Commands {
         #[clap(value_parser=maybe_hex::<usize>)]
         new_memory_size: usize
     },
+    SetMemorySizeUninit {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        new_memory_size: usize
+    },
+    AllocLazy {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        size: usize
+    },
+    SetMemorySizeAligned {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        new_memory_size: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        align: usize
+    },
+    GrowPreserve {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        new_size: usize
+    },
     Fill {
         #[clap(value_parser=maybe_hex::<u8>)]
         value: u8
@@ -28,120 +181,1976 @@ enum Commands {
         #[clap(value_parser=maybe_hex::<u64>)]
         seed: u64
     },
+    Touch,
+    Verify {
+        #[command(subcommand)]
+        pattern: Pattern
+    },
+    Checksum {
+        // a region instead of the memory
+        #[clap(long)]
+        region: Option<usize>
+    },
+    FillRange {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        start: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        length: usize,
+        #[clap(value_parser=maybe_hex::<u8>)]
+        value: u8
+    },
+    FillStride {
+        #[clap(value_parser=maybe_hex::<u8>)]
+        value: u8,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        stride: usize,
+        #[clap(value_parser=maybe_hex::<usize>, default_value = "0")]
+        offset: usize
+    },
+    CopyRange {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        src: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        dst: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        length: usize
+    },
+    CompareRange {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        a: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        b: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        length: usize
+    },
+    XorRange {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        start: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        length: usize,
+        #[clap(value_parser=maybe_hex::<u8>)]
+        key: u8
+    },
+    FillSequenceTyped {
+        ty: IntType,
+        #[clap(value_parser=maybe_hex_signed, allow_hyphen_values=true)]
+        start: i128,
+        #[clap(value_parser=maybe_hex_signed, allow_hyphen_values=true)]
+        step: i128
+    },
+    FillRandomFloat {
+        ty: FloatType,
+        #[clap(allow_hyphen_values=true)]
+        min: f64,
+        #[clap(allow_hyphen_values=true)]
+        max: f64,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        seed: u64
+    },
+    PlantValues {
+        ty: IntType,
+        #[clap(value_parser=maybe_hex_signed, allow_hyphen_values=true)]
+        value: i128,
+        count: usize,
+        #[clap(value_parser=maybe_hex::<u64>)]
+        seed: u64
+    },
+    QueryPlanted,
+    CountValue {
+        ty: IntType,
+        #[clap(value_parser=maybe_hex_signed, allow_hyphen_values=true)]
+        value: i128
+    },
     SetAddress {
         #[clap(value_parser=maybe_hex::<usize>)]
         address: usize,
         #[clap(value_parser=maybe_hex::<u8>)]
         value: u8
     },
+    IncrementAddress {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        address: usize,
+        #[clap(value_parser=maybe_hex_signed, allow_hyphen_values=true)]
+        delta: i128
+    },
+    Snapshot,
+    Restore,
+    AddManyRegions {
+        count: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        size: usize,
+        #[clap(long)]
+        label: Option<String>
+    },
+    AddRegionWithGap {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        size: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        gap: usize,
+        #[clap(long)]
+        label: Option<String>
+    },
+    MprotectRegion {
+        id: usize,
+        #[clap(value_parser=parse_prot)]
+        prot: i32
+    },
+    MmapFixed {
+        #[clap(value_parser=maybe_hex::<usize>)]
+        address: usize,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        size: usize
+    },
+    ShmRegion {
+        name: String,
+        #[clap(value_parser=maybe_hex::<usize>)]
+        size: usize,
+        #[clap(long)]
+        label: Option<String>
+    },
+    MmapFile {
+        path: std::path::PathBuf,
+        // default: the whole file
+        #[clap(value_parser=maybe_hex::<usize>)]
+        size: Option<usize>,
+        #[clap(long)]
+        read_only: bool,
+        #[clap(long)]
+        label: Option<String>
+    },
+    Mlock,
+    Munlock,
+    MadviseHugepage,
+    MadvisePageout {
+        // a region instead of the memory
+        #[clap(long)]
+        region: Option<usize>
+    },
+    StartMutator {
+        rate_hz: u32,
+        count: usize
+    },
+    StopMutator,
+    ForkChildren {
+        count: usize
+    },
+    Maps,
     Info
 }
 
-#[derive(Debug)]
-struct State {
-    memory: Vec<u8>
+/// Expected memory content for the verify command.
+#[derive(Subcommand, PartialEq, Eq, Debug)]
+enum Pattern {
+    AllZero,
+    Const {
+        #[clap(value_parser=maybe_hex::<u8>)]
+        value: u8
+    },
+    Seed {
+        #[clap(value_parser=maybe_hex::<u64>)]
+        seed: u64
+    }
 }
 
-static PROMPT: &str = "synthetic-load> ";
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum IntType {
+    I8,
+    U8,
+    I16,
+    U16,
+    I32,
+    U32,
+    I64,
+    U64
+}
 
-fn prepare_input_line(line: &String) -> Vec<String> {
-    let mut v: Vec<String> = line.split_ascii_whitespace().map(str::to_string).collect();
+impl IntType {
+    fn size(self) -> usize {
+        match self {
+            IntType::I8 | IntType::U8 => 1,
+            IntType::I16 | IntType::U16 => 2,
+            IntType::I32 | IntType::U32 => 4,
+            IntType::I64 | IntType::U64 => 8,
+        }
+    }
+}
 
-    // append PROMPT to front so clap will work
-    v.insert(0, PROMPT.to_string());
-    return v;
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum FloatType {
+    F32,
+    F64
 }
 
-fn set_memory_size(state: &mut State, new_size: usize) {
-    state.memory.resize(new_size, 0x0);
-    state.memory.shrink_to_fit();
+impl FloatType {
+    fn size(self) -> usize {
+        match self {
+            FloatType::F32 => 4,
+            FloatType::F64 => 8,
+        }
+    }
+
+    /// Why `Uniform::new_inclusive` would panic on `[min, max]` for this type, None if it won't. Besides the bounds it
+    /// needs the width, divided by the largest random fraction (just below 1), to be finite.
+    fn range_error(self, min: f64, max: f64) -> Option<String> {
+        if !min.is_finite() || !max.is_finite() || min > max {
+            return Some(format!("invalid range [{}, {}]", min, max));
+        }
+        let (name, lowest, highest, width_finite) = match self {
+            FloatType::F32 => ("f32", f32::MIN as f64, f32::MAX as f64, ((max as f32 - min as f32) / (1.0 - f32::EPSILON)).is_finite()),
+            FloatType::F64 => ("f64", f64::MIN, f64::MAX, ((max - min) / (1.0 - f64::EPSILON)).is_finite()),
+        };
+        if min < lowest || max > highest {
+            return Some(format!("range [{:e}, {:e}] is outside the finite {} values [{:e}, {:e}]", min, max, name, lowest, highest));
+        }
+        if !width_finite {
+            return Some(format!("range [{:e}, {:e}] is too wide for {}, max - min overflows", min, max, name));
+        }
+        return None;
+    }
 }
 
-fn fill_memory(state: &mut State, value: u8) {
-    state.memory.fill(value);
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum Backend {
+    Heap,
+    Mmap
 }
 
-fn fill_memory_random(state: &mut State, seed: u64) {
-    let mut rng = rand_pcg::Pcg64Mcg::seed_from_u64(seed);
-    let distr = rand::distributions::Uniform::new(u8::MIN, u8::MAX);
-    state.memory.fill_with(||rng.sample(distr));
+/// Random generator behind fill-random. xoshiro is rand's SmallRng, Xoshiro256++ on 64 bit targets.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum RngKind {
+    Pcg,
+    Xoshiro,
+    Chacha
 }
 
-fn set_address(state: &mut State, address: usize, value: u8) {
-    if state.memory.is_empty() {
-        println!("memory empty");
-        return;    
+impl RngKind {
+    fn name(&self) -> &'static str {
+        match self {
+            RngKind::Pcg => "pcg",
+            RngKind::Xoshiro => "xoshiro",
+            RngKind::Chacha => "chacha",
+        }
     }
+}
 
-    let memory_base_ptr = state.memory.as_ptr() as usize;
-    let memory_range = memory_base_ptr..memory_base_ptr + state.memory.len();
-    if !memory_range.contains(&address) {
-        println!("address not in range");
-        return;
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+enum Endian {
+    Little,
+    Big,
+    Native
+}
+
+/// Zero initialized heap allocation with a caller chosen base alignment.
+#[derive(Debug)]
+struct AlignedBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout
+}
+
+// The buffer exclusively owns its allocation, like a Vec<u8>.
+unsafe impl Send for AlignedBuffer {}
+
+impl AlignedBuffer {
+    fn new_zeroed(len: usize, align: usize) -> std::result::Result<AlignedBuffer, String> {
+        // always allocate at least one byte so the base address is meaningful
+        let layout = Layout::from_size_align(len.max(1), align).map_err(|e|e.to_string())?;
+        let ptr = NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) }).ok_or("allocation failed".to_string())?;
+        return Ok(AlignedBuffer{ ptr: ptr, len: len, layout: layout });
+    }
+
+    /// New buffer starting with as much of `prefix` as fits, the rest zeroed.
+    fn with_prefix(prefix: &[u8], len: usize, align: usize) -> std::result::Result<AlignedBuffer, String> {
+        let mut new = AlignedBuffer::new_zeroed(len, align)?;
+        let keep = len.min(prefix.len());
+        new[..keep].copy_from_slice(&prefix[..keep]);
+        return Ok(new);
     }
 
-    let index = address - memory_base_ptr;
-    state.memory[index] = value;
+    fn align(&self) -> usize {
+        return self.layout.align();
+    }
 }
 
-fn print_info(state: &State) {
-    println!("memory size: {:#x}", state.memory.len());
-    println!("memory start: {:#x}", state.memory.as_ptr() as usize);
-    println!("memory end: {:#x}", (state.memory.as_ptr() as usize) + state.memory.len())
+impl Drop for AlignedBuffer {
+    fn drop(&mut self) {
+        unsafe { std::alloc::dealloc(self.ptr.as_ptr(), self.layout) };
+    }
 }
 
-fn perform_command(state: &mut State, cli: Cli) {
-    match cli.command {
-        Commands::SetMemorySize { new_memory_size } => set_memory_size(state, new_memory_size),
-        Commands::Info => print_info(state),
-        Commands::Fill { value } => fill_memory(state, value),
-        Commands::FillRandom { seed } => fill_memory_random(state, seed),
-        Commands::SetAddress { address, value } => set_address(state, address, value),
-        _ => {
-            
+impl Deref for AlignedBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        return unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) };
+    }
+}
+
+impl DerefMut for AlignedBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        return unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) };
+    }
+}
+
+// from linux/mempolicy.h, libc doesn't have it
+const MPOL_BIND: libc::c_int = 2;
+
+/// Number of NUMA nodes the kernel knows about.
+fn numa_node_count() -> usize {
+    let Ok(entries) = std::fs::read_dir("/sys/devices/system/node") else {
+        return 1;
+    };
+    return entries.filter_map(|e| e.ok()).filter(|e| e.file_name().to_str().and_then(|n| n.strip_prefix("node")).is_some_and(|n| n.parse::<usize>().is_ok())).count().max(1);
+}
+
+/// Bind every later allocation of this process to `node`, so the memory lands there no matter how it is (re)allocated.
+fn bind_to_numa_node(node: usize) -> std::result::Result<(), String> {
+    if numa_node_count() <= 1 {
+        println!("Warning: single NUMA node system, ignoring --numa-node {}", node);
+        return Ok(());
+    }
+    let bits = libc::c_ulong::BITS as usize;
+    let mut nodemask: Vec<libc::c_ulong> = vec![0; node / bits + 1];
+    nodemask[node / bits] |= 1 << (node % bits);
+    // the kernel only reads maxnode - 1 bits, so pass one more like numactl does or the last node is dropped
+    if unsafe { libc::syscall(libc::SYS_set_mempolicy, MPOL_BIND, nodemask.as_ptr(), nodemask.len() * bits + 1) } != 0 {
+        return Err(format!("set_mempolicy to node {} failed: {}", node, std::io::Error::last_os_error()));
+    }
+    return Ok(());
+}
+
+fn page_size() -> usize {
+    return unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+}
+
+/// Private anonymous mapping, zero initialized by the kernel.
+#[derive(Debug)]
+struct MmapBuffer {
+    ptr: NonNull<u8>,
+    len: usize,
+    // bytes actually mapped, including any guard
+    mapped_len: usize,
+    // placed at a caller chosen address, resizing must not move it
+    fixed: bool
+}
+
+// The buffer exclusively owns its mapping, like a Vec<u8>.
+unsafe impl Send for MmapBuffer {}
+
+impl MmapBuffer {
+    fn new(len: usize) -> std::result::Result<MmapBuffer, String> {
+        return MmapBuffer::new_guarded(len, 0);
+    }
+
+    /// Mapping followed by `guard` bytes (a multiple of the page size) of PROT_NONE, which
+    /// keeps the kernel from merging it with an adjacent mapping. Guarded buffers can't be resized.
+    fn new_guarded(len: usize, guard: usize) -> std::result::Result<MmapBuffer, String> {
+        // mmap refuses zero length, map a single page instead but keep reporting len
+        let data_len = len.max(1).next_multiple_of(page_size());
+        let mapped_len = data_len + guard;
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), mapped_len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!("mmap failed: {}", std::io::Error::last_os_error()));
+        }
+        let buffer = MmapBuffer{ ptr: NonNull::new(ptr as *mut u8).unwrap(), len: len, mapped_len: mapped_len, fixed: false };
+        if guard > 0 && unsafe { libc::mprotect(buffer.ptr.as_ptr().add(data_len) as *mut libc::c_void, guard, libc::PROT_NONE) } != 0 {
+            return Err(format!("mprotect failed: {}", std::io::Error::last_os_error()));
         }
+        return Ok(buffer);
+    }
+
+    /// Mapping followed by an unmapped hole of at least `gap` bytes. The hole is reserved
+    /// together with the mapping and then unmapped, so nothing else was placed there at the time.
+    fn new_with_hole(len: usize, gap: usize) -> std::result::Result<MmapBuffer, String> {
+        let gap = gap.next_multiple_of(page_size());
+        let mut buffer = MmapBuffer::new_guarded(len, gap)?;
+        let data_len = buffer.mapped_len - gap;
+        if gap > 0 && unsafe { libc::munmap(buffer.ptr.as_ptr().add(data_len) as *mut libc::c_void, gap) } != 0 {
+            return Err(format!("munmap failed: {}", std::io::Error::last_os_error()));
+        }
+        buffer.mapped_len = data_len;
+        return Ok(buffer);
+    }
+
+    /// Mapping at exactly `address`, failing instead of relocating if anything is already mapped there.
+    fn new_fixed(address: usize, len: usize) -> std::result::Result<MmapBuffer, String> {
+        if !address.is_multiple_of(page_size()) {
+            return Err(format!("address {:#x} is not page aligned", address));
+        }
+        let mapped_len = len.max(1);
+        let ptr = unsafe { libc::mmap(address as *mut libc::c_void, mapped_len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED_NOREPLACE, -1, 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!("mmap at {:#x} failed: {}", address, std::io::Error::last_os_error()));
+        }
+        // kernels before 4.17 don't know MAP_FIXED_NOREPLACE and treat the address as a hint
+        if ptr as usize != address {
+            unsafe { libc::munmap(ptr, mapped_len) };
+            return Err(format!("kernel placed the mapping at {:#x} instead of {:#x}", ptr as usize, address));
+        }
+        return Ok(MmapBuffer{ ptr: NonNull::new(ptr as *mut u8).unwrap(), len: len, mapped_len: mapped_len, fixed: true });
+    }
+
+    /// Shared mapping of a new POSIX shared memory object `name` of `len` bytes, which other processes can map too.
+    /// The object is left in place, the caller unlinks it.
+    fn new_shm(name: &std::ffi::CStr, len: usize) -> std::result::Result<MmapBuffer, String> {
+        // pages past the end of the object would fault with SIGBUS
+        if len == 0 {
+            return Err("shm region size must be greater than 0".to_string());
+        }
+        let fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_CREAT | libc::O_EXCL | libc::O_RDWR, 0o600) };
+        if fd == -1 {
+            return Err(format!("shm_open {:?} failed: {}", name, std::io::Error::last_os_error()));
+        }
+        let ptr = match unsafe { libc::ftruncate(fd, len as libc::off_t) } {
+            0 => unsafe { libc::mmap(std::ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED, fd, 0) },
+            _ => libc::MAP_FAILED,
+        };
+        let error = std::io::Error::last_os_error();
+        // the mapping keeps the object alive, the descriptor isn't needed anymore
+        unsafe { libc::close(fd) };
+        if ptr == libc::MAP_FAILED {
+            unsafe { libc::shm_unlink(name.as_ptr()) };
+            return Err(format!("mapping shm {:?} failed: {}", name, error));
+        }
+        return Ok(MmapBuffer{ ptr: NonNull::new(ptr as *mut u8).unwrap(), len: len, mapped_len: len, fixed: true });
+    }
+
+    /// Shared mapping of the first `len` bytes of the file `file`. A writable mapping extends the file to `len` if it
+    /// is shorter, a read only one refuses to map past its end.
+    fn new_file(file: &std::fs::File, len: usize, read_only: bool) -> std::result::Result<MmapBuffer, String> {
+        // pages past the end of the file would fault with SIGBUS
+        let file_len = file.metadata().map_err(|e|e.to_string())?.len() as usize;
+        if len == 0 {
+            return Err("file region size must be greater than 0".to_string());
+        }
+        if file_len < len {
+            if read_only {
+                return Err(format!("file is only {:#x} bytes", file_len));
+            }
+            file.set_len(len as u64).map_err(|e| format!("extending file failed: {}", e))?;
+        }
+        let prot = if read_only { libc::PROT_READ } else { libc::PROT_READ | libc::PROT_WRITE };
+        let ptr = unsafe { libc::mmap(std::ptr::null_mut(), len, prot, libc::MAP_SHARED, file.as_raw_fd(), 0) };
+        if ptr == libc::MAP_FAILED {
+            return Err(format!("mmap failed: {}", std::io::Error::last_os_error()));
+        }
+        // the mapping keeps the file open, the caller can close it
+        return Ok(MmapBuffer{ ptr: NonNull::new(ptr as *mut u8).unwrap(), len: len, mapped_len: len, fixed: true });
+    }
+
+    /// Length of the accessible part of the mapping, i.e. `len` rounded up to whole pages.
+    fn data_len(&self) -> usize {
+        return self.len.max(1).next_multiple_of(page_size());
+    }
+
+    /// Grow or shrink in place if possible, otherwise let the kernel move the mapping (unless it is fixed).
+    fn resize(&mut self, new_len: usize) -> std::result::Result<(), String> {
+        let flags = if self.fixed { 0 } else { libc::MREMAP_MAYMOVE };
+        let ptr = unsafe { libc::mremap(self.ptr.as_ptr() as *mut libc::c_void, self.mapped_len, new_len.max(1), flags) };
+        if ptr == libc::MAP_FAILED {
+            if self.fixed {
+                return Err(format!("can't resize the fixed mapping at {:#x} in place: {}", self.ptr.as_ptr() as usize, std::io::Error::last_os_error()));
+            }
+            return Err(format!("mremap failed: {}", std::io::Error::last_os_error()));
+        }
+        let old_len = self.len;
+        self.ptr = NonNull::new(ptr as *mut u8).unwrap();
+        self.len = new_len;
+        self.mapped_len = new_len.max(1);
+        // pages past the old end are fresh zero pages, but the tail of the old
+        // last page still holds whatever was there before an earlier shrink
+        let stale_end = new_len.min(old_len.next_multiple_of(page_size()));
+        if stale_end > old_len {
+            self[old_len..stale_end].fill(0x0);
+        }
+        return Ok(());
     }
 }
 
-fn main() -> Result<()> {
-    // `()` can be used when no completer is required
-    let mut rl = DefaultEditor::new()?;
+impl Drop for MmapBuffer {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.mapped_len) };
+    }
+}
 
-    let mut state = State{ memory: vec![] };
+impl Deref for MmapBuffer {
+    type Target = [u8];
 
-    loop {
-        let readline = rl.readline(PROMPT);
-        match readline {
-            Ok(line) => {
-                let _ = rl.add_history_entry(line.as_str());
-                let res = Cli::try_parse_from(prepare_input_line(&line));
-                match res {
-                    Ok(cli) => {
-                        if cli.command == Commands::Exit {
-                            break;
-                        }
-                        perform_command(&mut state, cli);
-                        println!("Done");
-                    }
-                    Err(e) => {
-                        println!("{}", e);
-                        continue;
-                    }
-                }        
-            },
-            Err(ReadlineError::Interrupted) => {
-                println!("CTRL-C");
-                break
-            },
-            Err(ReadlineError::Eof) => {
-                println!("CTRL-D");
-                break
-            },
-            Err(err) => {
-                println!("Error: {:?}", err);
-                break
+    fn deref(&self) -> &[u8] {
+        return unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) };
+    }
+}
+
+impl DerefMut for MmapBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        return unsafe { std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) };
+    }
+}
+
+/// The scanned region. Derefs to its bytes so commands don't care how it was allocated.
+#[derive(Debug)]
+enum Memory {
+    Heap(Vec<u8>),
+    Aligned(AlignedBuffer),
+    Mmap(MmapBuffer)
+}
+
+impl Memory {
+    /// Resize keeping the existing prefix, new bytes are zero. May move the region. The heap backend
+    /// grows within its capacity when it can and only releases memory when shrinking with `release`.
+    fn resize(&mut self, new_size: usize, release: bool) -> std::result::Result<(), String> {
+        match self {
+            Memory::Heap(v) => {
+                let shrinking = new_size < v.len();
+                v.resize(new_size, 0x0);
+                if shrinking && release {
+                    v.shrink_to_fit();
+                }
             }
+            Memory::Aligned(b) => *b = AlignedBuffer::with_prefix(b, new_size, b.align())?,
+            Memory::Mmap(m) => m.resize(new_size)?,
         }
+        return Ok(());
     }
-    Ok(())
-}
\ No newline at end of file
+
+    fn kind(&self) -> &'static str {
+        match self {
+            Memory::Heap(_) => "heap",
+            Memory::Aligned(_) => "aligned",
+            Memory::Mmap(_) => "mmap",
+        }
+    }
+}
+
+impl Deref for Memory {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Memory::Heap(v) => v,
+            Memory::Aligned(b) => b,
+            Memory::Mmap(m) => m,
+        }
+    }
+}
+
+impl DerefMut for Memory {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        match self {
+            Memory::Heap(v) => v,
+            Memory::Aligned(b) => b,
+            Memory::Mmap(m) => m,
+        }
+    }
+}
+
+/// Additional mapping kept alongside the main memory, identified by its index in `State::regions`.
+#[derive(Debug)]
+struct Region {
+    buffer: MmapBuffer,
+    prot: i32,
+    // POSIX shared memory object backing the region, unlinked on exit
+    shm_name: Option<std::ffi::CString>,
+    // file the region maps, written back to it unless read only
+    file: Option<std::path::PathBuf>,
+    // what the region stands for, like "heap" or "stack", shown wherever regions are listed
+    label: Option<String>
+}
+
+/// Which buffer an address range lives in.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Location {
+    Memory,
+    Region(usize)
+}
+
+#[derive(Debug)]
+struct Mutator {
+    stop: Arc<AtomicBool>,
+    handle: JoinHandle<u64>
+}
+
+#[derive(Debug)]
+struct State {
+    // shared with the mutator thread, if one is running
+    memory: Arc<Mutex<Memory>>,
+    endian: Endian,
+    rng: RngKind,
+    mutator: Option<Mutator>,
+    snapshot: Option<Vec<u8>>,
+    // offsets written by the last plant-values, relative to the memory base
+    planted: Vec<usize>,
+    // held until exit so the mappings stay visible to scanmem
+    regions: Vec<Region>,
+    json: bool,
+    quiet: bool,
+    numbered: bool,
+    // children of fork-children, killed at exit
+    children: Vec<libc::pid_t>,
+    // commands answered so far, for the "Done #<n>" marker
+    completed: u64,
+    keep_capacity: bool
+}
+
+// Error message of a failed command, printed before its completion marker.
+type CommandResult = std::result::Result<(), String>;
+
+thread_local! {
+    // lines printed by the command a control socket client sent, None when printing to stdout
+    static CAPTURED_OUTPUT: RefCell<Option<Vec<String>>> = const { RefCell::new(None) };
+}
+
+/// Print a line of command output, to stdout or to the control socket client that sent the command.
+fn print_command_output(line: String) {
+    CAPTURED_OUTPUT.with_borrow_mut(|captured| match captured {
+        Some(lines) => lines.push(line),
+        None => println!("{}", line),
+    });
+}
+
+/// `println!` for what commands print, see `print_command_output`.
+macro_rules! command_println {
+    ($($arg:tt)*) => {
+        print_command_output(format!($($arg)*))
+    };
+}
+
+static PROMPT: &str = "synthetic-load> ";
+/// Where --deterministic-layout maps the memory, far from where the kernel places the heap, libraries and stacks.
+const DETERMINISTIC_MEMORY_ADDRESS: usize = 0x1000_0000_0000;
+static HISTORY_FILE_NAME: &str = ".synthetic_load_history";
+
+/// Completes the command name, the first word of the line. Arguments are not completed.
+struct CommandCompleter {
+    commands: Vec<String>
+}
+
+impl CommandCompleter {
+    fn new() -> CommandCompleter {
+        let commands = Cli::command().get_subcommands().flat_map(|c| std::iter::once(c.get_name()).chain(c.get_all_aliases())).map(str::to_string).collect();
+        return CommandCompleter{ commands: commands };
+    }
+}
+
+impl Completer for CommandCompleter {
+    type Candidate = String;
+
+    fn complete(&self, line: &str, pos: usize, _ctx: &Context<'_>) -> Result<(usize, Vec<String>)> {
+        let before_cursor = &line[..pos];
+        let start = before_cursor.len() - before_cursor.trim_start().len();
+        let word = &before_cursor[start..];
+        if word.contains(char::is_whitespace) {
+            return Ok((pos, vec![]));
+        }
+        return Ok((start, self.commands.iter().filter(|c| c.starts_with(word)).cloned().collect()));
+    }
+}
+
+impl Hinter for CommandCompleter {
+    type Hint = String;
+}
+
+impl Highlighter for CommandCompleter {}
+
+impl Validator for CommandCompleter {}
+
+impl Helper for CommandCompleter {}
+
+fn prepare_input_line(line: &str) -> Vec<String> {
+    let mut v: Vec<String> = line.split_ascii_whitespace().map(str::to_string).collect();
+
+    // append PROMPT to front so clap will work
+    v.insert(0, PROMPT.to_string());
+    return v;
+}
+
+/// Resize keeping the first min(old, new) bytes, any bytes past the old end are zero. The region may
+/// move (check `info`). The heap backend frees memory when shrinking (a reallocation and copy) unless
+/// --keep-capacity is given, and grows into spare capacity without reallocating when there is some.
+/// Forgets the planted values.
+fn set_memory_size(state: &mut State, new_size: usize) -> CommandResult {
+    state.memory.lock().unwrap().resize(new_size, !state.keep_capacity)?;
+    state.planted.clear();
+    return Ok(());
+}
+
+/// Faster `set_memory_size` for when the content is about to be overwritten anyway (e.g. by
+/// fill-random). The existing content is discarded and the new content is unspecified.
+///
+/// `set_memory_size` zero fills the new bytes itself, which touches every page before the fill
+/// touches them again. Handing out truly uninitialized bytes (`Vec::set_len`) is undefined behavior
+/// for a `[u8]`, so instead this takes fresh memory from the allocator/kernel: large zeroed heap
+/// allocations and anonymous mappings are backed by zero pages that are only faulted in on first
+/// write, so no page is written twice.
+fn set_memory_size_uninit(state: &mut State, new_size: usize) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let result = match &mut *memory {
+        Memory::Heap(v) => {
+            // release the old allocation first, its content is discarded anyway
+            *v = Vec::new();
+            // vec! with a zero element allocates through alloc_zeroed (calloc) instead of writing zeros
+            *v = vec![0x0; new_size];
+            Ok(())
+        }
+        Memory::Aligned(b) => AlignedBuffer::new_zeroed(new_size, b.align()).map(|new| *b = new),
+        // mremap doesn't touch the new pages either, and keeps a fixed mapping at its address
+        Memory::Mmap(m) if m.fixed => m.resize(new_size),
+        Memory::Mmap(m) => MmapBuffer::new(new_size).map(|new| *m = new),
+    };
+    result?;
+    state.planted.clear();
+    return Ok(());
+}
+
+/// Replace the memory with a fresh anonymous mapping of `size` that is never written, so no page is faulted in until
+/// something (scanmem) reads it. Unlike the heap backend's set-memory-size, which writes the zeros itself and so
+/// faults in every page, and set-memory-size-uninit, which leaves that to the fill that normally follows. The memory
+/// stays an mmap region afterwards, whatever the --backend.
+fn alloc_lazy(state: &mut State, size: usize) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    // unmap the old memory first so the two are never both mapped
+    *memory = Memory::Mmap(MmapBuffer::new(0)?);
+    *memory = Memory::Mmap(MmapBuffer::new(size)?);
+    state.planted.clear();
+    let start = memory.as_ptr() as usize;
+    command_println!("mapped: {:#x}-{:#x}", start, start + memory.len());
+    return Ok(());
+}
+
+/// Grow to `new_size` keeping every existing byte and the planted values, the new tail is zero. Unlike
+/// `set_memory_size` the heap backend reserves exactly `new_size`, without the spare capacity of
+/// amortized growth. The region may still move.
+fn grow_preserve(state: &mut State, new_size: usize) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    if new_size < memory.len() {
+        return Err(format!("new size {:#x} is smaller than the current size {:#x}", new_size, memory.len()));
+    }
+    match &mut *memory {
+        Memory::Heap(v) => {
+            v.reserve_exact(new_size - v.len());
+            v.resize(new_size, 0x0);
+        }
+        // these already reallocate to the exact size
+        other => other.resize(new_size, true)?,
+    }
+    return Ok(());
+}
+
+/// Like `set_memory_size` but moves the region into an allocation whose base is aligned to `align`.
+fn set_memory_size_aligned(state: &mut State, new_size: usize, align: usize) -> CommandResult {
+    if !align.is_power_of_two() {
+        return Err("alignment must be a power of two".to_string());
+    }
+    let mut memory = state.memory.lock().unwrap();
+    *memory = Memory::Aligned(AlignedBuffer::with_prefix(&memory, new_size, align)?);
+    state.planted.clear();
+    return Ok(());
+}
+
+fn fill_memory(state: &mut State, value: u8) -> CommandResult {
+    state.memory.lock().unwrap().fill(value);
+    return Ok(());
+}
+
+// fill-random generates each chunk of this size from its own seed so chunks can be filled in parallel
+const FILL_RANDOM_CHUNK_SIZE: usize = 0x100_000;
+
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    return z ^ (z >> 31);
+}
+
+/// Write the start of the byte stream of chunk `index` of `seed` into `chunk`, generated by `R`.
+fn fill_chunk_with<R: Rng + SeedableRng>(seed: u64, index: usize, chunk: &mut [u8]) {
+    let rng = R::seed_from_u64(splitmix64(seed ^ splitmix64(index as u64)));
+    // inclusive, a half open range would never produce 0xff
+    let distr = rand::distributions::Uniform::new_inclusive(u8::MIN, u8::MAX);
+    for (byte, value) in chunk.iter_mut().zip(rng.sample_iter(distr)) {
+        *byte = value;
+    }
+}
+
+/// Write the byte stream fill-random writes for chunk `index` of `seed` into `chunk`.
+fn fill_random_chunk(rng: RngKind, seed: u64, index: usize, chunk: &mut [u8]) {
+    match rng {
+        RngKind::Pcg => fill_chunk_with::<rand_pcg::Pcg64Mcg>(seed, index, chunk),
+        RngKind::Xoshiro => fill_chunk_with::<rand::rngs::SmallRng>(seed, index, chunk),
+        RngKind::Chacha => fill_chunk_with::<rand_chacha::ChaCha20Rng>(seed, index, chunk),
+    }
+}
+
+/// The byte stream fill-random writes for `seed`.
+fn random_bytes(rng: RngKind, seed: u64) -> impl Iterator<Item = u8> {
+    return (0..).flat_map(move |index| {
+        let mut chunk = vec![0u8; FILL_RANDOM_CHUNK_SIZE];
+        fill_random_chunk(rng, seed, index, &mut chunk);
+        return chunk;
+    });
+}
+
+/// Fill with the random stream of `seed`, in parallel. The content only depends on the seed,
+/// not on the number of threads. Note that it differs from the output of the earlier single
+/// threaded version, which used the seed directly for one stream over the whole memory.
+fn fill_memory_random(state: &mut State, seed: u64) -> CommandResult {
+    let rng = state.rng;
+    state.memory.lock().unwrap().par_chunks_mut(FILL_RANDOM_CHUNK_SIZE).enumerate().for_each(|(index, chunk)| {
+        fill_random_chunk(rng, seed, index, chunk);
+    });
+    return Ok(());
+}
+
+/// Compare the memory against `pattern`, regenerating the expected bytes on the fly. Prints "ok",
+/// a mismatch fails the command with its offset.
+fn verify_memory(state: &State, pattern: Pattern) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    let expected: Box<dyn Iterator<Item = u8>> = match pattern {
+        Pattern::AllZero => Box::new(std::iter::repeat(0x0)),
+        Pattern::Const { value } => Box::new(std::iter::repeat(value)),
+        Pattern::Seed { seed } => Box::new(random_bytes(state.rng, seed)),
+    };
+    match memory.iter().zip(expected).position(|(found, expected)| *found != expected) {
+        Some(offset) => return Err(format!("mismatch at offset {:#x}", offset)),
+        None => command_println!("ok"),
+    }
+    return Ok(());
+}
+
+/// CRC-32 (IEEE, as in zlib) lookup table.
+const CRC32_TABLE: [u32; 256] = {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+};
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc = CRC32_TABLE[((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    return !crc;
+}
+
+/// Print the CRC-32 of the memory or a region, to compare before and after something that should only read it.
+/// Reads every byte, so lazily allocated pages get faulted in. A region has to be readable.
+fn checksum_memory(state: &State, region: Option<usize>) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    let bytes: &[u8] = match region {
+        Some(id) => {
+            let region = state.regions.get(id).ok_or(format!("no region with id {}", id))?;
+            if region.prot & libc::PROT_READ == 0 {
+                return Err(format!("region {} is {} but {} is needed", id, prot_to_string(region.prot), prot_to_string(libc::PROT_READ)));
+            }
+            &region.buffer[..]
+        }
+        None => &memory[..],
+    };
+    command_println!("crc32: {:#010x}", crc32(bytes));
+    return Ok(());
+}
+
+/// Fault in every page of the memory by writing one byte per page, the byte is written
+/// back with its current value so the content is unchanged.
+fn touch_memory(state: &mut State) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let page_size = page_size();
+    let base = memory.as_ptr() as usize;
+    let mut pages: usize = 0;
+    let mut index = 0;
+    while index < memory.len() {
+        let byte = &mut memory[index] as *mut u8;
+        // volatile so the read-modify-write of an unchanged value isn't optimized away
+        unsafe { byte.write_volatile(byte.read_volatile()) };
+        pages += 1;
+        // first byte of the next page
+        index = (base + index + 1).next_multiple_of(page_size) - base;
+    }
+    command_println!("touched {} pages", pages);
+    return Ok(());
+}
+
+macro_rules! to_bytes {
+    ($value:expr, $endian:expr) => {
+        match $endian {
+            Endian::Little => $value.to_le_bytes(),
+            Endian::Big => $value.to_be_bytes(),
+            Endian::Native => $value.to_ne_bytes(),
+        }
+    };
+}
+
+/// Write `value` truncated to the width of `ty` into `out` (which must be `ty.size()` long).
+fn encode_int(ty: IntType, value: u64, endian: Endian, out: &mut [u8]) {
+    match ty.size() {
+        1 => out.copy_from_slice(&to_bytes!(value as u8, endian)),
+        2 => out.copy_from_slice(&to_bytes!(value as u16, endian)),
+        4 => out.copy_from_slice(&to_bytes!(value as u32, endian)),
+        _ => out.copy_from_slice(&to_bytes!(value, endian)),
+    }
+}
+
+fn fill_sequence_typed(state: &mut State, ty: IntType, start: i128, step: i128) -> CommandResult {
+    // Do the arithmetic in 64 bits with wrapping, truncating to the type width
+    // on encode is the same as wrapping in the chosen type.
+    let start = start as u64;
+    let step = step as u64;
+    let endian = state.endian;
+    let mut count: u64 = 0;
+    for element in state.memory.lock().unwrap().chunks_exact_mut(ty.size()) {
+        encode_int(ty, start.wrapping_add(step.wrapping_mul(count)), endian, element);
+        count += 1;
+    }
+    command_println!("wrote {} elements", count);
+    return Ok(());
+}
+
+/// Fill with floats uniformly distributed in `[min, max]`, drawn from one stream seeded with `seed`.
+fn fill_random_float(state: &mut State, ty: FloatType, min: f64, max: f64, seed: u64) -> CommandResult {
+    if let Some(e) = ty.range_error(min, max) {
+        return Err(e);
+    }
+    let mut memory = state.memory.lock().unwrap();
+    if !memory.len().is_multiple_of(ty.size()) {
+        return Err(format!("memory size {:#x} is not a multiple of {}", memory.len(), ty.size()));
+    }
+
+    let endian = state.endian;
+    let mut rng = rand_pcg::Pcg64Mcg::seed_from_u64(seed);
+    let elements = memory.chunks_exact_mut(ty.size());
+    let count = elements.len();
+    match ty {
+        FloatType::F32 => {
+            let distr = rand::distributions::Uniform::new_inclusive(min as f32, max as f32);
+            for element in elements {
+                element.copy_from_slice(&to_bytes!(rng.sample(distr), endian));
+            }
+        }
+        FloatType::F64 => {
+            let distr = rand::distributions::Uniform::new_inclusive(min, max);
+            for element in elements {
+                element.copy_from_slice(&to_bytes!(rng.sample(distr), endian));
+            }
+        }
+    }
+    command_println!("wrote {} elements", count);
+    return Ok(());
+}
+
+/// Write `value` at `count` distinct, randomly chosen, `ty` aligned offsets.
+/// Replaces any previously planted values.
+fn plant_values(state: &mut State, ty: IntType, value: i128, count: usize, seed: u64) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let elements = memory.len() / ty.size();
+    if count > elements {
+        return Err(format!("cannot plant {} values in {} elements", count, elements));
+    }
+
+    let mut rng = rand_pcg::Pcg64Mcg::seed_from_u64(seed);
+    let mut offsets: Vec<usize> = rand::seq::index::sample(&mut rng, elements, count).into_iter().map(|i| i * ty.size()).collect();
+    offsets.sort_unstable();
+    for offset in &offsets {
+        encode_int(ty, value as u64, state.endian, &mut memory[*offset..*offset + ty.size()]);
+    }
+    state.planted = offsets;
+    command_println!("planted {} values", count);
+    return Ok(());
+}
+
+/// Count the occurrences of `value` encoded as `ty`, at any byte offset (like scanmem matches them) and at `ty` aligned offsets.
+fn count_value(state: &State, ty: IntType, value: i128) -> CommandResult {
+    let mut needle = vec![0u8; ty.size()];
+    encode_int(ty, value as u64, state.endian, &mut needle);
+    let memory = state.memory.lock().unwrap();
+    let count = memory.par_windows(ty.size()).filter(|w| *w == needle.as_slice()).count();
+    let aligned_count = memory.par_chunks_exact(ty.size()).filter(|c| *c == needle.as_slice()).count();
+    command_println!("count: {}", count);
+    command_println!("aligned count: {}", aligned_count);
+    return Ok(());
+}
+
+fn query_planted(state: &State) -> CommandResult {
+    let memory_base_ptr = state.memory.lock().unwrap().as_ptr() as usize;
+    command_println!("planted count: {}", state.planted.len());
+    for offset in &state.planted {
+        command_println!("planted address: {:#x}", memory_base_ptr + offset);
+    }
+    return Ok(());
+}
+
+/// `address` relative to `base` in hex, negative below it.
+fn signed_offset(address: usize, base: usize) -> String {
+    if address < base {
+        return format!("-{:#x}", base - address);
+    }
+    return format!("{:#x}", address - base);
+}
+
+/// The error of every bounds checked command, like "address 0x1010 (offset 0x10) not in memory [0x1000, 0x1008)".
+/// `what` is the address or range that was checked.
+fn bounds_error(what: &str, memory: &[u8]) -> String {
+    let base = memory.as_ptr() as usize;
+    return format!("{} not in memory [{:#x}, {:#x})", what, base, base + memory.len());
+}
+
+/// Translate an absolute address into an index into `memory`.
+fn address_to_index(memory: &[u8], address: usize) -> std::result::Result<usize, String> {
+    // an empty Vec's pointer is dangling, don't show it as a range
+    if memory.is_empty() {
+        return Err("memory empty".to_string());
+    }
+    let memory_base_ptr = memory.as_ptr() as usize;
+    let memory_range = memory_base_ptr..memory_base_ptr + memory.len();
+    if !memory_range.contains(&address) {
+        return Err(bounds_error(&format!("address {:#x} (offset {})", address, signed_offset(address, memory_base_ptr)), memory));
+    }
+
+    return Ok(address - memory_base_ptr);
+}
+
+/// Translate the absolute address range `[start, start + length)` into an index range into `memory`.
+fn address_range_to_indices(memory: &[u8], start: usize, length: usize) -> std::result::Result<std::ops::Range<usize>, String> {
+    if memory.is_empty() {
+        return Err("memory empty".to_string());
+    }
+    let memory_base_ptr = memory.as_ptr() as usize;
+    let memory_range = memory_base_ptr..memory_base_ptr + memory.len();
+    let end = start.checked_add(length).ok_or("range overflows".to_string())?;
+    if start < memory_range.start || end > memory_range.end {
+        return Err(bounds_error(&format!("range [{:#x}, {:#x}) (offset {})", start, end, signed_offset(start, memory_base_ptr)), memory));
+    }
+
+    return Ok(start - memory_base_ptr..end - memory_base_ptr);
+}
+
+/// Find the memory or region fully containing `[start, start + length)`, checking that a
+/// region allows `prot` access so the caller can't fault on an mprotect'ed region.
+fn locate_range(memory: &[u8], regions: &[Region], start: usize, length: usize, prot: i32) -> std::result::Result<(Location, std::ops::Range<usize>), String> {
+    if let Ok(range) = address_range_to_indices(memory, start, length) {
+        return Ok((Location::Memory, range));
+    }
+    for (id, region) in regions.iter().enumerate() {
+        if let Ok(range) = address_range_to_indices(&region.buffer, start, length) {
+            if region.prot & prot != prot {
+                return Err(format!("region {} is {} but {} is needed", id, prot_to_string(region.prot), prot_to_string(prot)));
+            }
+            return Ok((Location::Region(id), range));
+        }
+    }
+    if memory.is_empty() {
+        return Err(format!("range [{:#x}, {:#x}) not in any region, memory empty", start, start.saturating_add(length)));
+    }
+    let what = format!("range [{:#x}, {:#x}) (offset {})", start, start.saturating_add(length), signed_offset(start, memory.as_ptr() as usize));
+    return Err(format!("{} or any region", bounds_error(&what, memory)));
+}
+
+fn location_bytes<'a>(memory: &'a [u8], regions: &'a [Region], location: Location) -> &'a [u8] {
+    match location {
+        Location::Memory => memory,
+        Location::Region(id) => &regions[id].buffer,
+    }
+}
+
+fn location_slice<'a>(memory: &'a mut [u8], regions: &'a mut [Region], location: Location) -> &'a mut [u8] {
+    match location {
+        Location::Memory => memory,
+        Location::Region(id) => &mut regions[id].buffer,
+    }
+}
+
+fn copy_range(state: &mut State, src: usize, dst: usize, length: usize) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let located = locate_range(&memory, &state.regions, src, length, libc::PROT_READ).and_then(|src| {
+        return Ok((src, locate_range(&memory, &state.regions, dst, length, libc::PROT_WRITE)?));
+    });
+    let ((src_location, src_range), (dst_location, dst_range)) = located?;
+
+    if src_location == dst_location {
+        // copy_within handles overlapping ranges
+        location_slice(&mut memory, &mut state.regions, src_location).copy_within(src_range, dst_range.start);
+    }
+    else {
+        let bytes = location_slice(&mut memory, &mut state.regions, src_location)[src_range].to_vec();
+        location_slice(&mut memory, &mut state.regions, dst_location)[dst_range].copy_from_slice(&bytes);
+    }
+    return Ok(());
+}
+
+/// Print "equal", differing ranges fail the command with "differ at <offset>", the offset of the first differing byte.
+fn compare_range(state: &State, a: usize, b: usize, length: usize) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    let located = locate_range(&memory, &state.regions, a, length, libc::PROT_READ).and_then(|a| {
+        return Ok((a, locate_range(&memory, &state.regions, b, length, libc::PROT_READ)?));
+    });
+    let ((a_location, a_range), (b_location, b_range)) = located?;
+
+    let a_bytes = &location_bytes(&memory, &state.regions, a_location)[a_range];
+    let b_bytes = &location_bytes(&memory, &state.regions, b_location)[b_range];
+    match a_bytes.iter().zip(b_bytes).position(|(a, b)| a != b) {
+        Some(offset) => return Err(format!("differ at {:#x}", offset)),
+        None => command_println!("equal"),
+    }
+    return Ok(());
+}
+
+fn xor_range(state: &mut State, start: usize, length: usize, key: u8) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let (location, range) = locate_range(&memory, &state.regions, start, length, libc::PROT_READ | libc::PROT_WRITE)?;
+    for byte in &mut location_slice(&mut memory, &mut state.regions, location)[range] {
+        *byte ^= key;
+    }
+    // a zero key leaves every byte as it was
+    command_println!("modified {} bytes", if key == 0 { 0 } else { length });
+    return Ok(());
+}
+
+fn fill_range(state: &mut State, start: usize, length: usize, value: u8) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let range = address_range_to_indices(&memory, start, length)?;
+    memory[range].fill(value);
+    command_println!("wrote {} bytes", length);
+    return Ok(());
+}
+
+/// Write `value` every `stride` bytes from `offset` (relative to the memory base), leaving the bytes in between as they are.
+fn fill_stride(state: &mut State, value: u8, stride: usize, offset: usize) -> CommandResult {
+    if stride == 0 {
+        return Err("stride must be greater than 0".to_string());
+    }
+    let mut memory = state.memory.lock().unwrap();
+    if offset > memory.len() && memory.is_empty() {
+        return Err("memory empty".to_string());
+    }
+    if offset > memory.len() {
+        let address = memory.as_ptr() as usize + offset;
+        return Err(bounds_error(&format!("address {:#x} (offset {:#x})", address, offset), &memory));
+    }
+    let mut writes: usize = 0;
+    for byte in memory[offset..].iter_mut().step_by(stride) {
+        *byte = value;
+        writes += 1;
+    }
+    command_println!("wrote {} bytes", writes);
+    return Ok(());
+}
+
+fn set_address(state: &mut State, address: usize, value: u8) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let index = address_to_index(&memory, address)?;
+    memory[index] = value;
+    return Ok(());
+}
+
+fn increment_address(state: &mut State, address: usize, delta: i128) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let index = address_to_index(&memory, address)?;
+    let old = memory[index];
+    memory[index] = old.wrapping_add(delta as u8);
+    command_println!("old value: {:#x}", old);
+    command_println!("new value: {:#x}", memory[index]);
+    return Ok(());
+}
+
+fn snapshot_memory(state: &mut State) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    // reuse the previous snapshot allocation when possible
+    let snapshot = state.snapshot.get_or_insert_with(Vec::new);
+    snapshot.clear();
+    snapshot.extend_from_slice(&memory);
+    command_println!("snapshot size: {:#x}", snapshot.len());
+    return Ok(());
+}
+
+fn restore_memory(state: &mut State) -> CommandResult {
+    let snapshot = match &state.snapshot {
+        Some(snapshot) => snapshot,
+        None => {
+            return Err("no snapshot taken".to_string());
+        }
+    };
+    let mut memory = state.memory.lock().unwrap();
+    // resizing would move the region, so refuse rather than invalidate addresses
+    if snapshot.len() != memory.len() {
+        return Err(format!("snapshot size {:#x} does not match memory size {:#x}", snapshot.len(), memory.len()));
+    }
+    memory.copy_from_slice(snapshot);
+    command_println!("restored size: {:#x}", snapshot.len());
+    return Ok(());
+}
+
+fn add_many_regions(state: &mut State, count: usize, size: usize, label: Option<String>) -> CommandResult {
+    state.regions.try_reserve(count).map_err(|e| format!("cannot add {} regions: {}", count, e))?;
+    for _ in 0..count {
+        // the guard page keeps each region a distinct mapping in /proc/pid/maps
+        let buffer = MmapBuffer::new_guarded(size, page_size())?;
+        state.regions.push(Region{ buffer: buffer, prot: libc::PROT_READ | libc::PROT_WRITE, shm_name: None, file: None, label: label.clone() });
+    }
+    command_println!("added {} regions", count);
+    return Ok(());
+}
+
+fn add_region_with_gap(state: &mut State, size: usize, gap: usize, label: Option<String>) -> CommandResult {
+    let buffer = MmapBuffer::new_with_hole(size, gap)?;
+    let start = buffer.as_ptr() as usize;
+    let mapped_end = start + buffer.mapped_len;
+    command_println!("region {}: {:#x}-{:#x}{}", state.regions.len(), start, start + buffer.len(), label.as_ref().map(|l| format!(" {}", l)).unwrap_or_default());
+    command_println!("gap: {:#x}-{:#x}", mapped_end, mapped_end + gap.next_multiple_of(page_size()));
+    state.regions.push(Region{ buffer: buffer, prot: libc::PROT_READ | libc::PROT_WRITE, shm_name: None, file: None, label: label });
+    return Ok(());
+}
+
+fn shm_region(state: &mut State, name: &str, size: usize, label: Option<String>) -> CommandResult {
+    // shm_open wants a single leading slash
+    let name = format!("/{}", name.trim_start_matches('/'));
+    let c_name = std::ffi::CString::new(name.as_str()).map_err(|e|e.to_string())?;
+    let buffer = MmapBuffer::new_shm(&c_name, size)?;
+    let start = buffer.as_ptr() as usize;
+    command_println!("region {}: {:#x}-{:#x}{}", state.regions.len(), start, start + buffer.len(), label.as_ref().map(|l| format!(" {}", l)).unwrap_or_default());
+    command_println!("shm name: {}", name);
+    state.regions.push(Region{ buffer: buffer, prot: libc::PROT_READ | libc::PROT_WRITE, shm_name: Some(c_name), file: None, label: label });
+    return Ok(());
+}
+
+/// Map `path` as a new region, shared so writes go to the file (created if missing) unless read only.
+/// The mapping stays until exit, madvise-pageout --region evicts its pages.
+fn mmap_file(state: &mut State, path: &std::path::Path, size: Option<usize>, read_only: bool, label: Option<String>) -> CommandResult {
+    let file = std::fs::OpenOptions::new().read(true).write(!read_only).create(!read_only).truncate(false).open(path).map_err(|e| format!("failed to open {}: {}", path.display(), e))?;
+    let size = match size {
+        Some(size) => size,
+        None => file.metadata().map_err(|e|e.to_string())?.len() as usize,
+    };
+    let buffer = MmapBuffer::new_file(&file, size, read_only).map_err(|e| format!("mapping {} failed: {}", path.display(), e))?;
+    let start = buffer.as_ptr() as usize;
+    command_println!("region {}: {:#x}-{:#x}{}", state.regions.len(), start, start + buffer.len(), label.as_ref().map(|l| format!(" {}", l)).unwrap_or_default());
+    let prot = if read_only { libc::PROT_READ } else { libc::PROT_READ | libc::PROT_WRITE };
+    state.regions.push(Region{ buffer: buffer, prot: prot, shm_name: None, file: Some(path.to_path_buf()), label: label });
+    return Ok(());
+}
+
+/// Remove the shared memory objects of shm regions, the mappings stay until exit.
+fn unlink_shm_regions(state: &mut State) {
+    for region in &mut state.regions {
+        if let Some(name) = region.shm_name.take() {
+            unsafe { libc::shm_unlink(name.as_ptr()) };
+        }
+    }
+}
+
+fn mprotect_region(state: &mut State, id: usize, prot: i32) -> CommandResult {
+    let region = match state.regions.get_mut(id) {
+        Some(region) => region,
+        None => {
+            return Err(format!("no region with id {}", id));
+        }
+    };
+    if unsafe { libc::mprotect(region.buffer.as_ptr() as *mut libc::c_void, region.buffer.data_len(), prot) } != 0 {
+        return Err(format!("mprotect failed: {}", std::io::Error::last_os_error()));
+    }
+    region.prot = prot;
+    return Ok(());
+}
+
+/// Move the memory to a mapping at `address`, keeping as much of the existing content as fits.
+fn mmap_fixed(state: &mut State, address: usize, size: usize) -> CommandResult {
+    let mut memory = state.memory.lock().unwrap();
+    let mut buffer = MmapBuffer::new_fixed(address, size)?;
+    let keep = size.min(memory.len());
+    buffer[..keep].copy_from_slice(&memory[..keep]);
+    *memory = Memory::Mmap(buffer);
+    state.planted.clear();
+    return Ok(());
+}
+
+fn mlock_memory(state: &mut State) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    if unsafe { libc::mlock(memory.as_ptr() as *const libc::c_void, memory.len()) } != 0 {
+        let e = std::io::Error::last_os_error();
+        match e.raw_os_error() {
+            Some(libc::ENOMEM) | Some(libc::EPERM) => return Err(format!("mlock failed: {} (raise the locked memory limit with `ulimit -l` or run with CAP_IPC_LOCK)", e)),
+            _ => return Err(format!("mlock failed: {}", e)),
+        }
+    }
+    // the lock doesn't follow the memory if a later resize moves it
+    command_println!("locked {:#x} bytes", memory.len());
+    return Ok(());
+}
+
+fn munlock_memory(state: &mut State) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    if unsafe { libc::munlock(memory.as_ptr() as *const libc::c_void, memory.len()) } != 0 {
+        return Err(format!("munlock failed: {}", std::io::Error::last_os_error()));
+    }
+    command_println!("unlocked {:#x} bytes", memory.len());
+    return Ok(());
+}
+
+fn madvise_hugepage(state: &mut State) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    if !(memory.as_ptr() as usize).is_multiple_of(page_size()) {
+        return Err("memory is not page aligned, use --backend mmap".to_string());
+    }
+    if unsafe { libc::madvise(memory.as_ptr() as *mut libc::c_void, memory.len(), libc::MADV_HUGEPAGE) } != 0 {
+        return Err(format!("MADV_HUGEPAGE rejected: {}", std::io::Error::last_os_error()));
+    }
+    command_println!("MADV_HUGEPAGE accepted");
+    return Ok(());
+}
+
+/// Ask the kernel to reclaim the memory's pages so the next access faults them back in. Unlike
+/// MADV_DONTNEED this keeps the content, which is lost for private anonymous memory otherwise.
+/// Anonymous pages can only go to swap, without swap they stay resident and this warns. Pages only
+/// partially covered by the memory are left alone.
+fn madvise_pageout(state: &mut State, region: Option<usize>) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    let (ptr, len) = match region {
+        Some(id) => state.regions.get(id).map(|r| (r.buffer.as_ptr(), r.buffer.len())).ok_or(format!("no region with id {}", id))?,
+        None => (memory.as_ptr(), memory.len()),
+    };
+    let start = (ptr as usize).next_multiple_of(page_size());
+    let end = (ptr as usize + len) / page_size() * page_size();
+    if end <= start {
+        command_println!("paged out 0 of 0 resident pages");
+        return Ok(());
+    }
+    let resident_before = resident_pages(start as *const u8, end - start)?;
+    if unsafe { libc::madvise(start as *mut libc::c_void, end - start, libc::MADV_PAGEOUT) } != 0 {
+        return Err(format!("MADV_PAGEOUT rejected: {}", std::io::Error::last_os_error()));
+    }
+    let resident_after = resident_pages(start as *const u8, end - start)?;
+    let paged_out = resident_before.saturating_sub(resident_after);
+    command_println!("paged out {} of {} resident pages", paged_out, resident_before);
+    if resident_before > 0 && paged_out == 0 {
+        command_println!("Warning: MADV_PAGEOUT paged out nothing, anonymous memory needs swap to be paged out");
+    }
+    return Ok(());
+}
+
+fn start_mutator(state: &mut State, rate_hz: u32, count: usize) -> CommandResult {
+    if state.mutator.is_some() {
+        return Err("mutator already running".to_string());
+    }
+    if rate_hz == 0 {
+        return Err("rate must be greater than 0".to_string());
+    }
+
+    let memory = Arc::clone(&state.memory);
+    let stop = Arc::new(AtomicBool::new(false));
+    let thread_stop = Arc::clone(&stop);
+    let period = Duration::from_secs_f64(1.0f64 / rate_hz as f64);
+    let handle = std::thread::spawn(move || {
+        let mut rng = rand::thread_rng();
+        let mut ticks: u64 = 0;
+        while !thread_stop.load(Ordering::Relaxed) {
+            {
+                let mut memory = memory.lock().unwrap();
+                if !memory.is_empty() {
+                    for _ in 0..count {
+                        let index = rng.gen_range(0..memory.len());
+                        memory[index] = rng.gen();
+                    }
+                }
+            }
+            ticks += 1;
+            std::thread::sleep(period);
+        }
+        return ticks;
+    });
+    state.mutator = Some(Mutator{ stop: stop, handle: handle });
+    return Ok(());
+}
+
+fn stop_mutator(state: &mut State) -> CommandResult {
+    match state.mutator.take() {
+        Some(mutator) => {
+            mutator.stop.store(true, Ordering::Relaxed);
+            let ticks = mutator.handle.join().unwrap();
+            command_println!("mutator stopped after {} ticks", ticks);
+        }
+        None => return Err("mutator not running".to_string()),
+    }
+    return Ok(());
+}
+
+/// Fork `count` children that sleep until killed, sharing every mapping (memory and regions) copy-on-write with us.
+fn fork_children(state: &mut State, count: usize) -> CommandResult {
+    // hold the memory still while forking so the children get a consistent copy of it
+    let memory = state.memory.lock().unwrap();
+    for _ in 0..count {
+        let pid = unsafe { libc::fork() };
+        if pid < 0 {
+            return Err(format!("fork failed after {} children: {}", state.children.len(), std::io::Error::last_os_error()));
+        }
+        if pid == 0 {
+            // only async signal safe calls from here on, other threads' locks may be held forever in the child.
+            // Die with the parent in case it never gets to kill us
+            unsafe {
+                libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGKILL);
+                loop {
+                    libc::pause();
+                }
+            }
+        }
+        state.children.push(pid);
+    }
+    let start = memory.as_ptr() as usize;
+    for pid in &state.children {
+        command_println!("child {}: {:#x}-{:#x} and {} regions copy-on-write", pid, start, start + memory.len(), state.regions.len());
+    }
+    return Ok(());
+}
+
+/// Kill and reap the children of fork-children.
+fn kill_children(state: &mut State) {
+    for pid in state.children.drain(..) {
+        unsafe {
+            libc::kill(pid, libc::SIGKILL);
+            libc::waitpid(pid, std::ptr::null_mut(), 0);
+        }
+    }
+}
+
+/// Resident set size of this process in bytes.
+fn process_rss() -> std::result::Result<usize, String> {
+    // statm is "size resident shared ..." in pages
+    let statm = std::fs::read_to_string("/proc/self/statm").map_err(|e|e.to_string())?;
+    let resident: usize = statm.split_ascii_whitespace().nth(1).ok_or("malformed /proc/self/statm".to_string())?.parse().map_err(|e: std::num::ParseIntError|e.to_string())?;
+    return Ok(resident * page_size());
+}
+
+/// Number of pages overlapping `[ptr, ptr + len)`.
+fn overlapping_pages(ptr: *const u8, len: usize) -> usize {
+    if len == 0 {
+        return 0;
+    }
+    let page_size = page_size();
+    return ((ptr as usize + len).next_multiple_of(page_size) - (ptr as usize) / page_size * page_size) / page_size;
+}
+
+/// Number of pages overlapping `[ptr, ptr + len)` that are currently resident.
+fn resident_pages(ptr: *const u8, len: usize) -> std::result::Result<usize, String> {
+    if len == 0 {
+        return Ok(0);
+    }
+    let page_size = page_size();
+    let start = (ptr as usize) / page_size * page_size;
+    let end = (ptr as usize + len).next_multiple_of(page_size);
+    let mut vec = vec![0u8; (end - start) / page_size];
+    if unsafe { libc::mincore(start as *mut libc::c_void, end - start, vec.as_mut_ptr()) } != 0 {
+        return Err(format!("mincore failed: {}", std::io::Error::last_os_error()));
+    }
+    return Ok(vec.iter().filter(|v| *v & 0x1 != 0).count());
+}
+
+fn print_info(state: &State) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    let start = memory.as_ptr() as usize;
+    // largest power of two the base address is a multiple of
+    let alignment = 1usize << start.trailing_zeros();
+    let pages = overlapping_pages(memory.as_ptr(), memory.len());
+    let resident = resident_pages(memory.as_ptr(), memory.len());
+    let rss = process_rss();
+    let region_total_size = state.regions.iter().map(|r| r.buffer.len()).sum::<usize>();
+
+    if state.json {
+        command_println!("{}", json!({
+            "backend": memory.kind(),
+            "rng": state.rng.name(),
+            "size": memory.len(),
+            "start": start,
+            "end": start + memory.len(),
+            "alignment": alignment,
+            "page_size": page_size(),
+            "pages": pages,
+            "resident_pages": resident.ok(),
+            "rss": rss.ok(),
+            "region_count": state.regions.len(),
+            "region_total_size": region_total_size,
+            "region_labels": state.regions.iter().map(|r| r.label.clone()).collect::<Vec<Option<String>>>(),
+            "mutator_running": state.mutator.is_some(),
+        }));
+        return Ok(());
+    }
+
+    command_println!("memory backend: {}", memory.kind());
+    command_println!("fill rng: {}", state.rng.name());
+    command_println!("memory size: {:#x}", memory.len());
+    command_println!("memory start: {:#x}", start);
+    command_println!("memory end: {:#x}", start + memory.len());
+    command_println!("memory alignment: {:#x}", alignment);
+    command_println!("page size: {:#x}", page_size());
+    command_println!("memory pages: {:#x} ({})", pages, pages);
+    match resident {
+        Ok(resident) => command_println!("memory resident pages: {:#x} ({})", resident, resident),
+        Err(e) => command_println!("memory resident pages: {}", e),
+    }
+    match rss {
+        Ok(rss) => command_println!("process rss: {:#x}", rss),
+        Err(e) => command_println!("process rss: {}", e),
+    }
+    command_println!("region count: {}", state.regions.len());
+    command_println!("region total size: {:#x}", region_total_size);
+    for (id, region) in state.regions.iter().enumerate() {
+        if let Some(label) = &region.label {
+            command_println!("region {} label: {}", id, label);
+        }
+    }
+    command_println!("mutator running: {}", state.mutator.is_some());
+    return Ok(());
+}
+
+/// Print the memory and every region as "start-end perms size name", like /proc/self/maps.
+fn print_maps(state: &State) -> CommandResult {
+    let memory = state.memory.lock().unwrap();
+    let mut maps = vec![(memory.as_ptr() as usize, memory.len(), libc::PROT_READ | libc::PROT_WRITE, "[memory]".to_string(), None)];
+    for (id, region) in state.regions.iter().enumerate() {
+        let mut name = format!("region {}", id);
+        if let Some(label) = &region.label {
+            name += &format!(" {}", label);
+        }
+        if let Some(shm_name) = &region.shm_name {
+            name += &format!(" shm {}", shm_name.to_string_lossy());
+        }
+        if let Some(file) = &region.file {
+            name += &format!(" file {}", file.display());
+        }
+        maps.push((region.buffer.as_ptr() as usize, region.buffer.len(), region.prot, format!("[{}]", name), region.label.clone()));
+    }
+
+    if state.json {
+        let maps: Vec<serde_json::Value> = maps.iter().map(|(start, len, prot, name, label)| json!({
+            "start": start,
+            "end": start + len,
+            "size": len,
+            "prot": prot_to_string(*prot),
+            "name": name,
+            "label": label,
+        })).collect();
+        command_println!("{}", json!({ "maps": maps }));
+        return Ok(());
+    }
+
+    for (start, len, prot, name, _) in maps {
+        command_println!("{:x}-{:x} {}p {:#x} {}", start, start + len, prot_to_string(prot), len, name);
+    }
+    return Ok(());
+}
+
+fn perform_command(state: &mut State, cli: Cli) -> CommandResult {
+    match cli.command {
+        Commands::SetMemorySize { new_memory_size } => set_memory_size(state, new_memory_size),
+        Commands::SetMemorySizeUninit { new_memory_size } => set_memory_size_uninit(state, new_memory_size),
+        Commands::AllocLazy { size } => alloc_lazy(state, size),
+        Commands::SetMemorySizeAligned { new_memory_size, align } => set_memory_size_aligned(state, new_memory_size, align),
+        Commands::GrowPreserve { new_size } => grow_preserve(state, new_size),
+        Commands::Info => print_info(state),
+        Commands::Maps => print_maps(state),
+        Commands::Fill { value } => fill_memory(state, value),
+        Commands::FillRandom { seed } => fill_memory_random(state, seed),
+        Commands::Touch => touch_memory(state),
+        Commands::Verify { pattern } => verify_memory(state, pattern),
+        Commands::Checksum { region } => checksum_memory(state, region),
+        Commands::FillRange { start, length, value } => fill_range(state, start, length, value),
+        Commands::FillStride { value, stride, offset } => fill_stride(state, value, stride, offset),
+        Commands::CopyRange { src, dst, length } => copy_range(state, src, dst, length),
+        Commands::CompareRange { a, b, length } => compare_range(state, a, b, length),
+        Commands::XorRange { start, length, key } => xor_range(state, start, length, key),
+        Commands::FillSequenceTyped { ty, start, step } => fill_sequence_typed(state, ty, start, step),
+        Commands::FillRandomFloat { ty, min, max, seed } => fill_random_float(state, ty, min, max, seed),
+        Commands::PlantValues { ty, value, count, seed } => plant_values(state, ty, value, count, seed),
+        Commands::QueryPlanted => query_planted(state),
+        Commands::CountValue { ty, value } => count_value(state, ty, value),
+        Commands::SetAddress { address, value } => set_address(state, address, value),
+        Commands::IncrementAddress { address, delta } => increment_address(state, address, delta),
+        Commands::Snapshot => snapshot_memory(state),
+        Commands::Restore => restore_memory(state),
+        Commands::AddManyRegions { count, size, label } => add_many_regions(state, count, size, label),
+        Commands::AddRegionWithGap { size, gap, label } => add_region_with_gap(state, size, gap, label),
+        Commands::MprotectRegion { id, prot } => mprotect_region(state, id, prot),
+        Commands::MmapFixed { address, size } => mmap_fixed(state, address, size),
+        Commands::ShmRegion { name, size, label } => shm_region(state, &name, size, label),
+        Commands::MmapFile { path, size, read_only, label } => mmap_file(state, &path, size, read_only, label),
+        Commands::Mlock => mlock_memory(state),
+        Commands::Munlock => munlock_memory(state),
+        Commands::MadviseHugepage => madvise_hugepage(state),
+        Commands::MadvisePageout { region } => madvise_pageout(state, region),
+        Commands::StartMutator { rate_hz, count } => start_mutator(state, rate_hz, count),
+        Commands::StopMutator => stop_mutator(state),
+        Commands::ForkChildren { count } => fork_children(state, count),
+        _ => Ok(()),
+    }
+}
+
+/// First line of an error, for the single line markers. Multi line (clap) errors continue with the usage.
+fn error_summary(e: &str) -> &str {
+    return e.lines().find(|l| !l.trim().is_empty()).unwrap_or("").trim_start_matches("error: ");
+}
+
+/// The single line quiet mode ends a command with.
+fn quiet_marker(result: &CommandResult) -> String {
+    return match result {
+        Ok(()) => "OK".to_string(),
+        Err(e) => format!("ERR {}", error_summary(e)),
+    };
+}
+
+/// One command or loop of a `run` script.
+enum ScriptItem {
+    Command(String),
+    Loop {
+        count: u64,
+        start: u64,
+        step: u64,
+        body: Vec<String>
+    }
+}
+
+/// Parse "loop <count> [<start> [<step>]]".
+fn parse_loop_header(line: &str) -> std::result::Result<(u64, u64, u64), String> {
+    let args: Vec<&str> = line.split_whitespace().skip(1).collect();
+    if args.is_empty() || args.len() > 3 {
+        return Err(format!("\"{}\": expected loop <count> [<start> [<step>]]", line));
+    }
+    let values: Vec<u64> = args.iter().map(|arg| maybe_hex::<u64>(arg)).collect::<std::result::Result<_, _>>().map_err(|e| format!("\"{}\": {}", line, e))?;
+    return Ok((values[0], values.get(1).copied().unwrap_or(0), values.get(2).copied().unwrap_or(1)));
+}
+
+/// Group the `run` commands into commands and loops, checking every loop before any command is performed.
+fn parse_script(commands: &[String]) -> std::result::Result<Vec<ScriptItem>, String> {
+    let is_keyword = |command: &str, keyword: &str| command.split_whitespace().next() == Some(keyword);
+    let mut items = vec![];
+    let mut index = 0;
+    while index < commands.len() {
+        let command = &commands[index];
+        if is_keyword(command, "endloop") {
+            return Err(format!("endloop (command {}) without loop", index + 1));
+        }
+        if !is_keyword(command, "loop") {
+            items.push(ScriptItem::Command(command.clone()));
+            index += 1;
+            continue;
+        }
+        let (count, start, step) = parse_loop_header(command)?;
+        let end = commands[index + 1..].iter().position(|c| is_keyword(c, "endloop")).ok_or(format!("\"{}\" without endloop", command))? + index + 1;
+        let body = commands[index + 1..end].to_vec();
+        if body.iter().any(|c| is_keyword(c, "loop")) {
+            return Err(format!("\"{}\": loops don't nest", command));
+        }
+        // the last value is the largest, so checking it covers every pass
+        let last = count.checked_sub(1).map_or(Some(start), |n| n.checked_mul(step).and_then(|offset| start.checked_add(offset)));
+        if last.is_none() {
+            return Err(format!("\"{}\": {{i}} overflows", command));
+        }
+        items.push(ScriptItem::Loop{ count: count, start: start, step: step, body: body });
+        index = end + 1;
+    }
+    return Ok(items);
+}
+
+/// Perform a parsed `run` script, stopping at "exit" or the first failing command. Returns false when a command failed.
+fn run_script(state: &Mutex<State>, script: &[ScriptItem]) -> bool {
+    for item in script {
+        match item {
+            ScriptItem::Command(command) => {
+                match execute_line(&mut state.lock().unwrap(), command) {
+                    None => return true,
+                    Some(Err(_)) => return false,
+                    Some(Ok(())) => {},
+                }
+            }
+            ScriptItem::Loop { count, start, step, body } => {
+                for n in 0..*count {
+                    // checked by parse_script
+                    let value = format!("{:#x}", start + n * step);
+                    for command in body {
+                        match execute_line(&mut state.lock().unwrap(), &command.replace("{i}", &value)) {
+                            None => return true,
+                            Some(Err(_)) => return false,
+                            Some(Ok(())) => {},
+                        }
+                    }
+                }
+            }
+        }
+    }
+    return true;
+}
+
+/// Parse and perform one command line. Returns its result, or None once the line asks to exit.
+fn execute_line(state: &mut State, line: &str) -> Option<CommandResult> {
+    let result = match Cli::try_parse_from(prepare_input_line(line)) {
+        Ok(cli) => {
+            if cli.command == Commands::Exit {
+                return None;
+            }
+            perform_command(state, cli)
+        }
+        Err(e) => {
+            // interactively the clap message (with usage) is all that is printed, there is no Done
+            if !state.json && !state.quiet && !state.numbered {
+                println!("{}", e);
+                return Some(Err(e.to_string()));
+            }
+            Err(e.to_string())
+        }
+    };
+
+    if state.json {
+        match &result {
+            Ok(()) => println!("{}", json!({ "status": "done" })),
+            Err(e) => println!("{}", json!({ "status": "error", "message": e })),
+        }
+    }
+    else if state.quiet {
+        println!("{}", quiet_marker(&result));
+    }
+    else if state.numbered {
+        state.completed += 1;
+        match &result {
+            Ok(()) => println!("Done #{}", state.completed),
+            Err(e) => println!("ERR #{} {}", state.completed, error_summary(e)),
+        }
+    }
+    else {
+        if let Err(e) = &result {
+            println!("{}", e);
+        }
+        println!("Done");
+    }
+    return Some(result);
+}
+
+/// Perform the commands of one control socket client, answering each with its output and quiet marker. "exit" closes
+/// the connection.
+fn serve_control_client(state: &Mutex<State>, stream: UnixStream) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        CAPTURED_OUTPUT.set(Some(vec![]));
+        let result = match Cli::try_parse_from(prepare_input_line(&line)) {
+            Ok(cli) if cli.command == Commands::Exit => break,
+            Ok(cli) => perform_command(&mut state.lock().unwrap(), cli),
+            Err(e) => Err(e.to_string()),
+        };
+        for output in CAPTURED_OUTPUT.take().unwrap_or_default() {
+            writeln!(writer, "{}", output)?;
+        }
+        writeln!(writer, "{}", quiet_marker(&result))?;
+    }
+    return Ok(());
+}
+
+/// Accept control socket clients on their own threads, for as long as the process runs.
+fn start_control_socket(state: &Arc<Mutex<State>>, path: &std::path::Path) -> std::io::Result<()> {
+    let listener = UnixListener::bind(path)?;
+    let state = Arc::clone(state);
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let Ok(stream) = stream else {
+                continue;
+            };
+            let state = Arc::clone(&state);
+            std::thread::spawn(move || {
+                if let Err(e) = serve_control_client(&state, stream) {
+                    println!("Warning: control socket client failed: {}", e);
+                }
+            });
+        }
+    });
+    return Ok(());
+}
+
+fn run_repl(state: &Mutex<State>, history_file: Option<std::path::PathBuf>) -> Result<()> {
+    let mut rl: Editor<CommandCompleter, DefaultHistory> = Editor::new()?;
+    rl.set_helper(Some(CommandCompleter::new()));
+
+    if let Some(path) = &history_file {
+        match rl.load_history(path) {
+            Ok(()) => {},
+            // first run, nothing saved yet
+            Err(ReadlineError::Io(e)) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => println!("Warning: failed to load history from {}: {}", path.display(), e),
+        }
+    }
+
+    loop {
+        let quiet = state.lock().unwrap().quiet;
+        let readline = rl.readline(if quiet { "" } else { PROMPT });
+        match readline {
+            Ok(line) => {
+                let _ = rl.add_history_entry(line.as_str());
+                if execute_line(&mut state.lock().unwrap(), &line).is_none() {
+                    break;
+                }
+            },
+            Err(ReadlineError::Interrupted) => {
+                println!("CTRL-C");
+                break
+            },
+            Err(ReadlineError::Eof) => {
+                println!("CTRL-D");
+                break
+            },
+            Err(err) => {
+                println!("Error: {:?}", err);
+                break
+            }
+        }
+    }
+
+    if let Some(path) = &history_file {
+        if let Err(e) = rl.save_history(path) {
+            println!("Warning: failed to save history to {}: {}", path.display(), e);
+        }
+    }
+    return Ok(());
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if let Some(node) = args.numa_node {
+        if let Err(e) = bind_to_numa_node(node) {
+            println!("{}", e);
+            std::process::exit(1);
+        }
+    }
+
+    let memory = match (args.deterministic_layout, args.backend) {
+        (true, _) => match MmapBuffer::new_fixed(DETERMINISTIC_MEMORY_ADDRESS, 0) {
+            Ok(buffer) => Memory::Mmap(buffer),
+            Err(e) => {
+                println!("Can't honor --deterministic-layout: {}", e);
+                std::process::exit(1);
+            }
+        },
+        (false, Backend::Heap) => Memory::Heap(vec![]),
+        (false, Backend::Mmap) => Memory::Mmap(MmapBuffer::new(0).expect("initial mmap")),
+    };
+    if args.deterministic_layout {
+        match args.json {
+            true => println!("{}", json!({ "layout": { "memory": format!("{:#x}", DETERMINISTIC_MEMORY_ADDRESS), "page_size": page_size() } })),
+            false => println!("layout: memory {:#x}, page size {:#x}", DETERMINISTIC_MEMORY_ADDRESS, page_size()),
+        }
+    }
+    // shared with the control socket clients, if any
+    let state = Arc::new(Mutex::new(State{ memory: Arc::new(Mutex::new(memory)), endian: args.endian, rng: args.rng, mutator: None, snapshot: None, planted: vec![], regions: vec![], json: args.json, quiet: args.quiet, numbered: args.numbered, children: vec![], completed: 0, keep_capacity: args.keep_capacity }));
+
+    if let Some(path) = &args.control_socket {
+        start_control_socket(&state, path)?;
+    }
+
+    if let Some(fd) = args.ready_fd {
+        // SAFETY: the fd is handed to us by the parent for this purpose only, and closed when dropped
+        let mut ready = unsafe { std::fs::File::from_raw_fd(fd) };
+        if let Err(e) = ready.write_all(b"\n") {
+            println!("Failed to signal ready on fd {}: {}", fd, e);
+            std::process::exit(1);
+        }
+    }
+
+    let mut script_failed = false;
+    match args.mode {
+        Some(Mode::Run { commands }) => {
+            match parse_script(&commands) {
+                Ok(script) => script_failed = !run_script(&state, &script),
+                Err(e) => {
+                    println!("{}", e);
+                    script_failed = true;
+                }
+            }
+        }
+        None => {
+            let history_file = match args.no_history {
+                true => None,
+                false => args.history_file.or_else(|| std::env::var_os("HOME").map(|home| std::path::PathBuf::from(home).join(HISTORY_FILE_NAME))),
+            };
+            run_repl(&state, history_file)?;
+        }
+    }
+
+    if let Some(path) = &args.control_socket {
+        let _ = std::fs::remove_file(path);
+    }
+
+    // never leave the mutator thread writing while the process tears down
+    let mut state = state.lock().unwrap();
+    if state.mutator.is_some() {
+        stop_mutator(&mut state).unwrap();
+    }
+    unlink_shm_regions(&mut state);
+    kill_children(&mut state);
+    if script_failed {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn empty_state() -> State {
+        return State{ memory: Arc::new(Mutex::new(Memory::Heap(vec![]))), endian: Endian::Native, rng: RngKind::Pcg, mutator: None, snapshot: None, planted: vec![], regions: vec![], json: false, quiet: false, numbered: false, children: vec![], completed: 0, keep_capacity: false };
+    }
+
+    #[test]
+    fn verify_fails_on_mismatch() {
+        let state = empty_state();
+        *state.memory.lock().unwrap() = Memory::Heap(vec![0x0; 0x100]);
+        assert!(verify_memory(&state, Pattern::AllZero).is_ok());
+        assert_eq!(verify_memory(&state, Pattern::Const { value: 0x1 }), Err("mismatch at offset 0x0".to_string()));
+    }
+
+    #[test]
+    fn compare_range_fails_when_different() {
+        let state = empty_state();
+        *state.memory.lock().unwrap() = Memory::Heap(vec![0x0, 0x0, 0x0, 0x1]);
+        let base = state.memory.lock().unwrap().as_ptr() as usize;
+        assert!(compare_range(&state, base, base + 1, 2).is_ok());
+        assert_eq!(compare_range(&state, base, base + 2, 2), Err("differ at 0x1".to_string()));
+    }
+
+    #[test]
+    fn address_in_empty_memory() {
+        assert_eq!(address_to_index(&[], 0x0), Err("memory empty".to_string()));
+    }
+
+    #[test]
+    fn add_many_regions_rejects_impossible_count() {
+        let mut state = empty_state();
+        assert!(add_many_regions(&mut state, usize::MAX, 0x1000, None).is_err());
+        assert!(state.regions.is_empty());
+    }
+
+    #[test]
+    fn checksum_rejects_unreadable_region() {
+        let mut state = empty_state();
+        add_many_regions(&mut state, 1, 0x1000, None).unwrap();
+        assert!(checksum_memory(&state, Some(0)).is_ok());
+        mprotect_region(&mut state, 0, libc::PROT_NONE).unwrap();
+        assert!(checksum_memory(&state, Some(0)).is_err());
+    }
+
+    #[test]
+    fn fill_random_produces_every_byte_value() {
+        for rng in RngKind::value_variants() {
+            let mut chunk = vec![0u8; FILL_RANDOM_CHUNK_SIZE];
+            fill_random_chunk(*rng, 1, 0, &mut chunk);
+            let mut seen = [false; 256];
+            for byte in &chunk {
+                seen[*byte as usize] = true;
+            }
+            assert!(seen.iter().all(|s| *s), "{} misses byte values", rng.name());
+        }
+    }
+}
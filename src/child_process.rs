@@ -0,0 +1,444 @@
+use std::{collections::VecDeque, fs::File, io::{BufRead, BufReader, BufWriter, Read, Write}, os::fd::{FromRawFd, RawFd}, os::unix::process::{CommandExt, ExitStatusExt}, process::{Child, ChildStderr, ChildStdin, ChildStdout, Command, ExitStatus, Stdio}, sync::{atomic::{AtomicBool, Ordering}, mpsc::{self, RecvTimeoutError, Sender}, Arc, Mutex, OnceLock}, thread::JoinHandle, time::{Duration, Instant}};
+use regex::Regex;
+use crate::{log_print, log_println};
+
+static ECHO_START: OnceLock<Instant> = OnceLock::new();
+
+/// Start of the error `ChildProcess::wait_or_kill` returns when the child ran out of time.
+pub static TIMEOUT_ERROR: &str = "timed out";
+
+/// How long a child gets to exit after SIGTERM before it is sent SIGKILL.
+const TERMINATE_GRACE: Duration = Duration::from_secs(1);
+const WAIT_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Prefix every echoed line with the time since `start`, like "[+1.234s]".
+pub fn enable_echo_timestamps(start: Instant) {
+    ECHO_START.set(start).unwrap();
+}
+
+fn echo_timestamp() -> String {
+    return match ECHO_START.get() {
+        Some(start) => format!("[+{:.3}s] ", start.elapsed().as_secs_f64()),
+        None => String::new(),
+    };
+}
+
+/// The last `limit` lines that went through the child's pipes, tagged with the stream, oldest first.
+#[derive(Default)]
+struct CapturedLines {
+    limit: usize,
+    lines: VecDeque<String>,
+}
+
+impl CapturedLines {
+    fn push(&mut self, stream: &str, line: &str) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.lines.len() == self.limit {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(format!("{}: {}", stream, line.trim_end_matches('\n')));
+    }
+}
+
+/// Thread that terminates a child once its time is up, like `ChildProcess::terminate`, unless stopped first.
+/// It must be stopped before the child is reaped, after that the pid may belong to another process.
+struct Watchdog {
+    // dropping it stops the thread
+    cancel: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+    fired: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    fn start(pid: u32, timeout: Duration) -> Watchdog {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let fired = Arc::new(AtomicBool::new(false));
+        let thread_fired = Arc::clone(&fired);
+        let handle = std::thread::spawn(move || {
+            if cancelled.recv_timeout(timeout) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            thread_fired.store(true, Ordering::Relaxed);
+            unsafe { libc::kill(pid as libc::pid_t, libc::SIGTERM) };
+            if cancelled.recv_timeout(TERMINATE_GRACE) == Err(RecvTimeoutError::Timeout) {
+                unsafe { libc::kill(pid as libc::pid_t, libc::SIGKILL) };
+            }
+        });
+        return Watchdog{ cancel: Some(cancel), handle: Some(handle), fired: fired };
+    }
+
+    fn stop(&mut self) {
+        drop(self.cancel.take());
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+/// Child process with piped stdio, optionally echoing everything it reads and writes.
+pub struct ChildProcess {
+    pub child_process: Child,
+    pub stdin: BufWriter<ChildStdin>,
+    pub stdout: BufReader<ChildStdout>,
+    /// None once handed to a reader thread by `collect_stderr`.
+    pub stderr: Option<BufReader<ChildStderr>>,
+    pub echo: bool,
+    // shared with the stderr reader thread
+    captured: Arc<Mutex<CapturedLines>>,
+    // set by `kill_after`
+    watchdog: Option<Watchdog>,
+}
+
+impl ChildProcess {
+    /// Spawn `command` with `args` passed as is, one argument each, no shell splitting or quoting.
+    pub fn new(command: &str, args: &[String], echo: bool) -> Result<ChildProcess, String> {
+        return Self::spawn(command, args, None, echo);
+    }
+
+    /// Like `new`, but also pass the write end of a pipe as `--ready-fd=<n>` and block until the child writes
+    /// to it, so there is no need to scrape stdout to know it is ready for commands.
+    pub fn new_when_ready(command: &str, args: &[String], echo: bool) -> Result<ChildProcess, String> {
+        let mut fds: [RawFd; 2] = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(format!("pipe: {}", std::io::Error::last_os_error()))
+        }
+        // owned here so both ends are closed on every return
+        let ready_read = unsafe { File::from_raw_fd(fds[0]) };
+        let ready_write = unsafe { File::from_raw_fd(fds[1]) };
+        let mut args = args.to_vec();
+        args.push(format!("--ready-fd={}", fds[1]));
+        let child = Self::spawn(command, &args, Some(fds[1]), echo)?;
+        // without our copy of the write end, the read sees EOF if the child exits before it is ready
+        drop(ready_write);
+        let mut byte = [0u8; 1];
+        match (&ready_read).read(&mut byte) {
+            Ok(1) => {},
+            Ok(_) => return Err(format!("pid {} closed its ready fd without signaling ready", child.child_process.id())),
+            Err(e) => return Err(format!("pid {} ready fd: {}", child.child_process.id(), e)),
+        }
+        return Ok(child)
+    }
+
+    /// Spawn with piped stdio, `inherit_fd` is kept open across exec.
+    fn spawn(command: &str, args: &[String], inherit_fd: Option<RawFd>, echo: bool) -> Result<ChildProcess, String> {
+        let mut cmd = Command::new(command);
+        cmd.args(args).stdin(Stdio::piped()).stdout(Stdio::piped()).stderr(Stdio::piped());
+        if let Some(fd) = inherit_fd {
+            // clearing FD_CLOEXEC in the forked child only, the parent's copy still closes on exec of other children
+            unsafe {
+                cmd.pre_exec(move || {
+                    if libc::fcntl(fd, libc::F_SETFD, 0) == -1 {
+                        return Err(std::io::Error::last_os_error());
+                    }
+                    return Ok(());
+                });
+            }
+        }
+        let mut c = match cmd.spawn() {
+            Ok(c) => c,
+            Err(e) => {
+                let hint = match e.kind() {
+                    std::io::ErrorKind::NotFound => "program not found, check the path or that it is on PATH",
+                    std::io::ErrorKind::PermissionDenied => "permission denied, check that the program is executable",
+                    _ => "spawn failed",
+                };
+                return Err(format!("failed to start \"{}\" with args {:?}: {} ({})", command, args, hint, e))
+            }
+        };
+        let stdin = BufWriter::new(c.stdin.take().unwrap());
+        let stdout = BufReader::new(c.stdout.take().unwrap());
+        let stderr = BufReader::new(c.stderr.take().unwrap());
+
+        return Ok(ChildProcess{child_process: c, stdin: stdin, stdout: stdout, stderr: Some(stderr), echo: echo, captured: Arc::default(), watchdog: None})
+    }
+
+    /// Keep the last `lines` lines read from stdout and stderr and written to stdin, 0 (the default) keeps none.
+    /// Enable before `collect_stderr` so stderr is included.
+    pub fn capture_output(&mut self, lines: usize) {
+        let mut captured = self.captured.lock().unwrap();
+        captured.limit = lines;
+        while captured.lines.len() > lines {
+            captured.lines.pop_front();
+        }
+    }
+
+    /// The lines kept by `capture_output`, like "stdout: ...", oldest first.
+    pub fn captured_output(&self) -> Vec<String> {
+        return self.captured.lock().unwrap().lines.iter().cloned().collect();
+    }
+
+    pub fn read_until_line(&mut self, condition_line: &str) -> Result<(), String> {
+        self.read_lines_until(condition_line)?;
+        return Ok(())
+    }
+
+    /// Read stdout until `condition_line`, returning the lines before it (without newlines).
+    pub fn read_lines_until(&mut self, condition_line: &str) -> Result<Vec<String>, String> {
+        let mut lines = vec![];
+        loop {
+            let mut buf = String::new();
+            let len = self.stdout.read_line(&mut buf).map_err(|e|e.to_string())?;
+            if len == 0 {
+                return Err(format!("pid {} closed stdout before printing \"{}\"", self.child_process.id(), condition_line));
+            }
+            if self.echo {
+                log_print!("{}pid {} stdout: {}", echo_timestamp(), self.child_process.id(), buf);
+            }
+            self.captured.lock().unwrap().push("stdout", &buf);
+            if buf.eq(format!("{}\n", condition_line).as_str()) {
+                return Ok(lines)
+            }
+            lines.push(buf.trim_end_matches('\n').to_string());
+        }
+    }
+
+    /// Read stdout until a line (without its newline) matches `re`, returning the lines before it and the matching line.
+    pub fn read_until_matching(&mut self, re: &Regex) -> Result<(Vec<String>, String), String> {
+        let mut lines = vec![];
+        loop {
+            let mut buf = String::new();
+            let len = self.stdout.read_line(&mut buf).map_err(|e|e.to_string())?;
+            if len == 0 {
+                return Err(format!("pid {} closed stdout before printing a line matching \"{}\"", self.child_process.id(), re));
+            }
+            if self.echo {
+                log_print!("{}pid {} stdout: {}", echo_timestamp(), self.child_process.id(), buf);
+            }
+            self.captured.lock().unwrap().push("stdout", &buf);
+            let line = buf.trim_end_matches('\n');
+            if re.is_match(line) {
+                return Ok((lines, line.to_string()))
+            }
+            lines.push(line.to_string());
+        }
+    }
+
+    /// Read stdout until it ends with `prompt` (prompts are not newline terminated), returning the output before it.
+    pub fn read_until_prompt(&mut self, prompt: &str) -> Result<String, String> {
+        return self.read_until_prompt_with(prompt, |_| {});
+    }
+
+    /// Like `read_until_prompt`, also calling `on_line` with every complete line (without newline) as soon as it arrives.
+    pub fn read_until_prompt_with<F>(&mut self, prompt: &str, mut on_line: F) -> Result<String, String> where F: FnMut(&str) {
+        let mut out: Vec<u8> = vec![];
+        let mut line_start = 0;
+        while !out.ends_with(prompt.as_bytes()) {
+            let mut byte = [0u8; 1];
+            let len = self.stdout.read(&mut byte).map_err(|e|e.to_string())?;
+            if len == 0 {
+                return Err(format!("pid {} closed stdout before printing prompt \"{}\"", self.child_process.id(), prompt));
+            }
+            out.push(byte[0]);
+            if byte[0] == b'\n' {
+                on_line(&String::from_utf8_lossy(&out[line_start..out.len() - 1]));
+                line_start = out.len();
+            }
+        }
+        out.truncate(out.len() - prompt.len());
+        let out = String::from_utf8_lossy(&out).to_string();
+        {
+            let mut captured = self.captured.lock().unwrap();
+            for line in out.lines() {
+                captured.push("stdout", line);
+            }
+        }
+        if self.echo {
+            log_println!("{}pid {} stdout: {}{}", echo_timestamp(), self.child_process.id(), out, prompt);
+        }
+        return Ok(out)
+    }
+
+    /// Read stderr on a separate thread until the child closes it, so it can't fill the pipe and block the child.
+    /// Join the handle after the child has exited to get the lines (without newlines).
+    pub fn collect_stderr(&mut self) -> Result<JoinHandle<Vec<String>>, String> {
+        let mut stderr = self.stderr.take().ok_or("stderr already taken".to_string())?;
+        let pid = self.child_process.id();
+        let echo = self.echo;
+        let captured = Arc::clone(&self.captured);
+        return Ok(std::thread::spawn(move || {
+            let mut lines = vec![];
+            loop {
+                let mut buf = String::new();
+                match stderr.read_line(&mut buf) {
+                    Ok(0) | Err(_) => return lines,
+                    Ok(_) => {},
+                }
+                if echo {
+                    log_print!("{}pid {} stderr: {}", echo_timestamp(), pid, buf);
+                }
+                captured.lock().unwrap().push("stderr", &buf);
+                lines.push(buf.trim_end_matches('\n').to_string());
+            }
+        }))
+    }
+
+    /// Terminate the child (SIGTERM, then SIGKILL) once `timeout` has passed from now, even while blocked reading from
+    /// it. Reads then fail with EOF, check `timed_out`. Only reap the child through the methods here afterwards, not
+    /// through `child_process` directly, so the pid is never signaled after it was reaped.
+    pub fn kill_after(&mut self, timeout: Duration) {
+        self.stop_watchdog();
+        self.watchdog = Some(Watchdog::start(self.child_process.id(), timeout));
+    }
+
+    /// Whether the child was terminated by `kill_after`.
+    pub fn timed_out(&self) -> bool {
+        return self.watchdog.as_ref().is_some_and(|w| w.fired.load(Ordering::Relaxed));
+    }
+
+    fn stop_watchdog(&mut self) {
+        if let Some(watchdog) = &mut self.watchdog {
+            watchdog.stop();
+        }
+    }
+
+    /// Wait for the child to exit, terminating it once `timeout` has passed. The error then starts with `TIMEOUT_ERROR`.
+    pub fn wait_or_kill(&mut self, timeout: Duration) -> Result<ExitStatus, String> {
+        // this reaps the child itself
+        self.stop_watchdog();
+        if let Some(status) = self.wait_timeout(timeout)? {
+            return Ok(status)
+        }
+        self.terminate()?;
+        return Err(format!("{} after {:.3}s, killed pid {}", TIMEOUT_ERROR, timeout.as_secs_f64(), self.child_process.id()))
+    }
+
+    /// Poll for the exit status until `timeout` has passed, None if the child is still running then.
+    fn wait_timeout(&mut self, timeout: Duration) -> Result<Option<ExitStatus>, String> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(status) = self.child_process.try_wait().map_err(|e|e.to_string())? {
+                return Ok(Some(status))
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(None)
+            }
+            std::thread::sleep(WAIT_POLL_INTERVAL.min(deadline - now));
+        }
+    }
+
+    /// SIGTERM the child, then SIGKILL it if it hasn't exited within `TERMINATE_GRACE`, and reap it.
+    fn terminate(&mut self) -> Result<ExitStatus, String> {
+        unsafe { libc::kill(self.child_process.id() as libc::pid_t, libc::SIGTERM) };
+        if let Some(status) = self.wait_timeout(TERMINATE_GRACE)? {
+            return Ok(status)
+        }
+        self.child_process.kill().map_err(|e|e.to_string())?;
+        return self.child_process.wait().map_err(|e|e.to_string());
+    }
+
+    /// Reap the child with wait4, returning its exit status and resource usage.
+    /// `child_process` must not be waited on afterwards, the pid is gone.
+    pub fn wait_with_rusage(&mut self) -> Result<(ExitStatus, libc::rusage), String> {
+        let pid = self.child_process.id() as libc::pid_t;
+        if self.watchdog.is_some() {
+            // wait for the exit without reaping, so the watchdog can still be running until the child is gone
+            let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+            while unsafe { libc::waitid(libc::P_PID, pid as libc::id_t, &mut info, libc::WEXITED | libc::WNOWAIT) } != 0 {
+                let err = std::io::Error::last_os_error();
+                if err.kind() != std::io::ErrorKind::Interrupted {
+                    return Err(format!("waitid on pid {} failed: {}", pid, err))
+                }
+            }
+            self.stop_watchdog();
+        }
+        let mut status: libc::c_int = 0;
+        let mut rusage: libc::rusage = unsafe { std::mem::zeroed() };
+        loop {
+            let ret = unsafe { libc::wait4(pid, &mut status, 0, &mut rusage) };
+            if ret == pid {
+                return Ok((ExitStatus::from_raw(status), rusage))
+            }
+            let err = std::io::Error::last_os_error();
+            if err.kind() != std::io::ErrorKind::Interrupted {
+                return Err(format!("wait4 on pid {} failed: {}", pid, err))
+            }
+        }
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<(), String> {
+        let out = format!("{}\n", line);
+        if self.echo {
+            log_print!("{}pid {} stdin: {}", echo_timestamp(), self.child_process.id(), out);
+        }
+        self.captured.lock().unwrap().push("stdin", line);
+        self.stdin.write_all(out.as_bytes()).map_err(|e|e.to_string())?;
+        self.stdin.flush().map_err(|e|e.to_string())?;
+        return Ok(())
+    }
+}
+
+impl Drop for ChildProcess {
+    fn drop(&mut self) {
+        // before try_wait below, which may reap the child
+        self.stop_watchdog();
+        // a child that is still running would keep its output pipes open forever, and outlive us if not echoing.
+        // try_wait fails when the child was already reaped by wait_with_rusage, there is nothing to kill then
+        if let Ok(None) = self.child_process.try_wait() {
+            let _ = self.terminate();
+        }
+        if self.echo {
+            // Read whats left in the output pipes
+            loop {
+                let mut buf = String::new();
+                let len = self.stdout.read_line(&mut buf).unwrap();
+                if len == 0 {
+                    break;
+                }
+                log_print!("{}pid {} stdout: {}", echo_timestamp(), self.child_process.id(), buf);
+            }
+            if let Some(stderr) = &mut self.stderr {
+                loop {
+                    let mut buf = String::new();
+                    let len = stderr.read_line(&mut buf).unwrap();
+                    if len == 0 {
+                        break;
+                    }
+                    log_print!("{}pid {} stderr: {}", echo_timestamp(), self.child_process.id(), buf);
+                }
+            }
+        }
+        log_println!("Dropping ChildProcess pid {}", self.child_process.id());
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Whether `pid` has been reaped, waitpid fails with ECHILD then.
+    fn is_reaped(pid: u32) -> bool {
+        let ret = unsafe { libc::waitpid(pid as libc::pid_t, std::ptr::null_mut(), libc::WNOHANG) };
+        return ret == -1 && std::io::Error::last_os_error().raw_os_error() == Some(libc::ECHILD);
+    }
+
+    #[test]
+    fn wait_or_kill_times_out_and_reaps() {
+        let mut child = ChildProcess::new("sleep", &["10".to_string()], false).unwrap();
+        let start = Instant::now();
+        let err = child.wait_or_kill(Duration::from_millis(100)).unwrap_err();
+        assert!(err.starts_with(TIMEOUT_ERROR), "{}", err);
+        // sleep dies on SIGTERM, no need to wait for the SIGKILL grace
+        assert!(start.elapsed() < TERMINATE_GRACE);
+        assert!(is_reaped(child.child_process.id()));
+    }
+
+    #[test]
+    fn wait_or_kill_returns_exit_status() {
+        let mut child = ChildProcess::new("true", &[], false).unwrap();
+        assert!(child.wait_or_kill(Duration::from_secs(10)).unwrap().success());
+    }
+
+    #[test]
+    fn kill_after_interrupts_a_blocked_read() {
+        let mut child = ChildProcess::new("sleep", &["10".to_string()], false).unwrap();
+        child.kill_after(Duration::from_millis(100));
+        assert!(child.read_until_line("never printed").is_err());
+        assert!(child.timed_out());
+        let (status, _) = child.wait_with_rusage().unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+    }
+}
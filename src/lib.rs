@@ -0,0 +1,40 @@
+use std::path::PathBuf;
+
+pub mod affinity;
+pub mod child_process;
+pub mod log;
+
+pub static SYNTHETIC_LOAD_NAME: &str = "synthetic_load";
+/// Default --pid-arg-template, how scanmem is told the pid to attach to.
+pub static DEFAULT_PID_ARG_TEMPLATE: &str = "--pid={pid}";
+/// Default --threads-arg-template, how scanmem is told the thread count.
+pub static DEFAULT_THREADS_ARG_TEMPLATE: &str = "-j={threads}";
+
+/// Resolve `program` the way spawning it would: a path if it contains '/', otherwise the first match on PATH.
+pub fn find_program(program: &str) -> Option<PathBuf> {
+    if program.contains('/') {
+        return std::fs::canonicalize(program).ok().filter(|p| p.is_file());
+    }
+    let path = std::env::var_os("PATH")?;
+    return std::env::split_paths(&path).map(|dir| dir.join(program)).find(|p| p.is_file());
+}
+
+/// Split `template` on whitespace into arguments and replace `placeholder` in each with `value`.
+fn expand_arg_template(template: &str, placeholder: &str, value: &str) -> Vec<String> {
+    return template.split_whitespace().map(|arg| arg.replace(placeholder, value)).collect();
+}
+
+/// scanmem's arguments to attach to `pid` and scan with `nthreads` threads, from the --pid-arg-template and
+/// --threads-arg-template. The thread count is left out for -1.
+pub fn scanmem_args(pid_arg_template: &str, threads_arg_template: &str, pid: u32, nthreads: i32) -> Vec<String> {
+    let mut args = expand_arg_template(pid_arg_template, "{pid}", &pid.to_string());
+    if nthreads != -1 {
+        args.extend(expand_arg_template(threads_arg_template, "{threads}", &nthreads.to_string()));
+    }
+    return args;
+}
+
+/// The synthetic_load binary, built next to the running executable.
+pub fn default_synthetic_load_path() -> PathBuf {
+    return std::env::current_exe().unwrap().parent().unwrap().to_path_buf().join(SYNTHETIC_LOAD_NAME);
+}
@@ -0,0 +1,39 @@
+use std::{fs::File, io::Write, sync::Mutex};
+
+static LOG_FILE: Mutex<Option<File>> = Mutex::new(None);
+
+/// Duplicate everything printed through `log_print!`/`log_println!` into `path` from now on.
+pub fn set_log_file(path: &str) -> Result<(), String> {
+    let file = File::create(path).map_err(|e| format!("{}: {}", path, e))?;
+    *LOG_FILE.lock().unwrap() = Some(file);
+    return Ok(())
+}
+
+/// Write `s` to stdout and to the log file, if one is set. Both writes happen under the log lock so lines
+/// printed from different threads don't interleave differently in the terminal and the file.
+pub fn tee(s: &str) {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    let mut stdout = std::io::stdout().lock();
+    stdout.write_all(s.as_bytes()).unwrap();
+    stdout.flush().unwrap();
+    if let Some(file) = log_file.as_mut() {
+        // losing the log is no reason to abort a run
+        let _ = file.write_all(s.as_bytes());
+    }
+}
+
+/// `print!` that also goes to the log file.
+#[macro_export]
+macro_rules! log_print {
+    ($($arg:tt)*) => {
+        $crate::log::tee(&format!($($arg)*))
+    };
+}
+
+/// `println!` that also goes to the log file.
+#[macro_export]
+macro_rules! log_println {
+    ($($arg:tt)*) => {
+        $crate::log::tee(&format!("{}\n", format!($($arg)*)))
+    };
+}